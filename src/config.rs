@@ -0,0 +1,702 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use std::collections::HashMap;
+use std::env::VarError;
+use std::error::Error;
+#[cfg(feature = "tls")]
+use std::fs;
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::path::PathBuf;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, fmt};
+
+use http::{HeaderName, HeaderValue, Uri};
+use serde::Deserialize;
+#[cfg(feature = "tls")]
+use ureq::tls::{Certificate, ClientCert, PemItem, PrivateKey, RootCerts, TlsConfig, parse_pem};
+
+#[cfg(all(feature = "kv", any(feature = "logfmt", feature = "json", feature = "pattern")))]
+use crate::ErrorChain;
+#[cfg(feature = "json")]
+use crate::JsonFormatter;
+#[cfg(feature = "pattern")]
+use crate::PatternFormatter;
+#[cfg(any(feature = "logfmt", feature = "json"))]
+use crate::{AutoFields, FieldNames, LevelNames};
+use crate::{Directives, FailurePolicy, InternalLogPolicy, LokiBuilder, LokiFormatter, MultilineStrategy};
+#[cfg(feature = "logfmt")]
+use crate::{FieldOrder, LogfmtFormatter};
+
+impl LokiBuilder {
+    /// Construct a Loki builder from environment variables. The following variables are read:
+    ///  - `LOKI_ENDPOINT` - the push endpoint, e.g. `https://loki.example.com/loki/api/v1/push`. Required.
+    ///  - `LOKI_LABELS` - comma separated `name=value` pairs, e.g. `app=api,env=prod`. Required.
+    ///  - `LOKI_HEADERS` - comma separated `name=value` pairs sent as HTTP headers
+    ///  - `LOKI_TENANT` - the tenant ID, sent as the `X-Scope-OrgID` header
+    ///  - `LOKI_USERNAME` and `LOKI_PASSWORD` - credentials for HTTP basic authentication
+    ///  - `LOKI_LOG` - env_logger style directives, e.g. `info,my_crate=debug`
+    ///  - `LOKI_BATCH_SIZE` - see `.max_logs()`
+    ///  - `LOKI_BATCH_TIMEOUT` - see `.max_log_lifetime()`, in seconds
+    ///  - `LOKI_COMPRESS` - `true` or `false`, see `.compress()`
+    ///
+    /// Unset variables keep their defaults. A variable that is set to a malformed value, or to a
+    /// value that isn't valid unicode, results in an error.
+    pub fn from_env() -> Result<LokiBuilder, ConfigError> {
+        LokiBuilder::from_vars(|var| match env::var(var) {
+            Ok(value) => Ok(Some(value)),
+            Err(VarError::NotPresent) => Ok(None),
+            Err(VarError::NotUnicode(_)) => Err(ConfigError::new(var, "not valid unicode")),
+        })
+    }
+
+    fn from_vars(
+        var: impl Fn(&'static str) -> Result<Option<String>, ConfigError>,
+    ) -> Result<LokiBuilder, ConfigError> {
+        let endpoint = var("LOKI_ENDPOINT")?.ok_or(ConfigError::new("LOKI_ENDPOINT", "not set"))?;
+        let endpoint: Uri = endpoint
+            .parse()
+            .map_err(|_| ConfigError::new("LOKI_ENDPOINT", "invalid URI"))?;
+
+        let labels = var("LOKI_LABELS")?.ok_or(ConfigError::new("LOKI_LABELS", "not set"))?;
+        let labels = parse_pairs("LOKI_LABELS", &labels)?;
+        if labels.is_empty() {
+            return Err(ConfigError::new("LOKI_LABELS", "at least one label is required"));
+        }
+        if !labels.keys().all(|name| is_label_name(name)) {
+            return Err(ConfigError::new("LOKI_LABELS", "invalid label name"));
+        }
+
+        let mut builder = LokiBuilder::new(endpoint, labels);
+
+        if let Some(headers) = var("LOKI_HEADERS")? {
+            for (name, value) in parse_pairs("LOKI_HEADERS", &headers)? {
+                builder = builder.header("LOKI_HEADERS", &name, &value)?;
+            }
+        }
+
+        if let Some(tenant) = var("LOKI_TENANT")? {
+            builder = builder.header("LOKI_TENANT", "X-Scope-OrgID", &tenant)?;
+        }
+
+        match (var("LOKI_USERNAME")?, var("LOKI_PASSWORD")?) {
+            (Some(username), Some(password)) => {
+                let credentials = base64(format!("{username}:{password}").as_bytes());
+                builder = builder.header("LOKI_PASSWORD", "Authorization", &format!("Basic {credentials}"))?;
+            },
+            (None, None) => {},
+            (None, Some(_)) => return Err(ConfigError::new("LOKI_USERNAME", "not set, but LOKI_PASSWORD is")),
+            (Some(_), None) => return Err(ConfigError::new("LOKI_PASSWORD", "not set, but LOKI_USERNAME is")),
+        }
+
+        if let Some(directives) = var("LOKI_LOG")? {
+            let directives: Directives = directives
+                .parse()
+                .map_err(|_| ConfigError::new("LOKI_LOG", "invalid directives"))?;
+            builder = builder.directives(directives);
+        }
+
+        if let Some(size) = var("LOKI_BATCH_SIZE")? {
+            match size.trim().parse() {
+                Ok(size) if size > 0 => builder = builder.max_logs(size),
+                _ => return Err(ConfigError::new("LOKI_BATCH_SIZE", "expected a positive integer")),
+            }
+        }
+
+        if let Some(timeout) = var("LOKI_BATCH_TIMEOUT")? {
+            let timeout = timeout
+                .trim()
+                .parse()
+                .ok()
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .ok_or(ConfigError::new("LOKI_BATCH_TIMEOUT", "expected a number of seconds"))?;
+            builder = builder.max_log_lifetime(timeout);
+        }
+
+        if let Some(compress) = var("LOKI_COMPRESS")? {
+            let compress = parse_bool(&compress).ok_or(ConfigError::new("LOKI_COMPRESS", "expected true or false"))?;
+
+            #[cfg(feature = "compress")]
+            {
+                builder = builder.compress(compress);
+            }
+            #[cfg(not(feature = "compress"))]
+            if compress {
+                return Err(ConfigError::new("LOKI_COMPRESS", "the compress feature is disabled"));
+            }
+        }
+
+        Ok(builder)
+    }
+
+    // Like add_header, but validates the header first
+    fn header(self, var: &'static str, name: &str, value: &str) -> Result<LokiBuilder, ConfigError> {
+        HeaderName::try_from(name).map_err(|_| ConfigError::new(var, "invalid header name"))?;
+        HeaderValue::try_from(value).map_err(|_| ConfigError::new(var, "invalid header value"))?;
+        Ok(self.add_header(name, value))
+    }
+}
+
+/// Returned when the configuration of a `LokiBuilder` is invalid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    key: String,
+    reason: &'static str,
+}
+
+impl ConfigError {
+    fn new(key: &str, reason: &'static str) -> Self {
+        ConfigError {
+            key: String::from(key),
+            reason,
+        }
+    }
+
+    /// The environment variable or configuration key that is invalid.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration for {}: {}", self.key, self.reason)
+    }
+}
+
+impl Error for ConfigError {}
+
+/// `LokiConfig` mirrors the options of `LokiBuilder`, so that logging can be configured from a
+/// configuration file in any format supported by serde, such as TOML or YAML. Pass it to
+/// `LokiBuilder::from_config()`. Options that are left out keep their defaults.
+///
+/// ```toml
+/// endpoint = "https://loki.example.com/loki/api/v1/push"
+/// labels = { app = "api", env = "prod" }
+/// level = "info,my_crate=debug"
+/// failure_policy = { retry = 6 }
+/// formatter = { type = "json", fields = ["level", "target"] }
+/// tls = { ca_file = "/etc/loki/ca.pem" }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LokiConfig {
+    pub endpoint: String,
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub max_logs: Option<usize>,
+    /// In seconds
+    pub max_log_lifetime: Option<f64>,
+    pub max_line_length: Option<usize>,
+    pub max_field_length: Option<usize>,
+    pub multiline: Option<MultilineStrategy>,
+    pub failure_policy: Option<FailurePolicy>,
+    pub internal_log_policy: Option<InternalLogPolicy>,
+    /// A level or env_logger style directives, e.g. `info,my_crate=debug`
+    pub level: Option<String>,
+    pub compress: Option<bool>,
+    pub formatter: Option<FormatterConfig>,
+    pub tls: Option<TlsFiles>,
+    pub trace_context: Option<TraceContext>,
+}
+
+/// `FormatterConfig` selects one of the built-in formatters and its options. Fields are given by
+/// the names of the `AutoFields` flags, e.g. `["level", "module_path"]`. If they are left out, the
+/// formatter's default fields are used.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FormatterConfig {
+    #[cfg(feature = "logfmt")]
+    Logfmt {
+        fields: Option<Vec<String>>,
+        #[serde(default)]
+        escape_newlines: bool,
+        #[serde(default)]
+        render_line: bool,
+        #[serde(default)]
+        field_order: FieldOrder,
+        #[serde(default)]
+        field_names: FieldNames,
+        #[serde(default)]
+        level_names: LevelNames,
+        #[cfg(feature = "kv")]
+        #[serde(default)]
+        error_chain: ErrorChain,
+    },
+    #[cfg(feature = "json")]
+    Json {
+        fields: Option<Vec<String>>,
+        #[serde(default)]
+        field_names: FieldNames,
+        #[serde(default)]
+        level_names: LevelNames,
+        #[cfg(feature = "kv")]
+        #[serde(default)]
+        error_chain: ErrorChain,
+    },
+    #[cfg(feature = "pattern")]
+    Pattern {
+        template: String,
+        #[cfg(feature = "kv")]
+        #[serde(default)]
+        error_chain: ErrorChain,
+    },
+}
+
+/// `TraceContext` names the structured fields that the IDs of the current OpenTelemetry span are
+/// attached as, like `LokiBuilder::trace_context()`. It requires the `opentelemetry` feature.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+/// `TlsFiles` points to PEM files used to connect to Loki over TLS. `ca_file` replaces the
+/// default root certificates. `cert_file` and `key_file` enable client authentication and must
+/// be given together.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsFiles {
+    pub ca_file: Option<PathBuf>,
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+}
+
+impl LokiBuilder {
+    /// Construct a Loki builder from a `LokiConfig`. Returns an error if an option has a value
+    /// that is not valid, or if a TLS file cannot be read.
+    pub fn from_config(config: LokiConfig) -> Result<LokiBuilder, ConfigError> {
+        let endpoint: Uri = config
+            .endpoint
+            .parse()
+            .map_err(|_| ConfigError::new("endpoint", "invalid URI"))?;
+        if config.labels.is_empty() {
+            return Err(ConfigError::new("labels", "at least one label is required"));
+        }
+        if !config.labels.keys().all(|name| is_label_name(name)) {
+            return Err(ConfigError::new("labels", "invalid label name"));
+        }
+
+        let mut builder = LokiBuilder::new(endpoint, config.labels);
+
+        for (name, value) in &config.headers {
+            builder = builder.header("headers", name, value)?;
+        }
+        if let Some(lines) = config.max_logs {
+            if lines == 0 {
+                return Err(ConfigError::new("max_logs", "expected a positive integer"));
+            }
+            builder = builder.max_logs(lines);
+        }
+        if let Some(secs) = config.max_log_lifetime {
+            let lifetime = Duration::try_from_secs_f64(secs)
+                .map_err(|_| ConfigError::new("max_log_lifetime", "expected a number of seconds"))?;
+            builder = builder.max_log_lifetime(lifetime);
+        }
+        if let Some(bytes) = config.max_line_length {
+            builder = builder.max_line_length(bytes);
+        }
+        #[cfg(feature = "kv")]
+        if let Some(bytes) = config.max_field_length {
+            builder = builder.max_field_length(bytes);
+        }
+        #[cfg(not(feature = "kv"))]
+        if config.max_field_length.is_some() {
+            return Err(ConfigError::new("max_field_length", "the kv feature is disabled"));
+        }
+        if let Some(strategy) = config.multiline {
+            builder = builder.multiline(strategy);
+        }
+        if let Some(fp) = config.failure_policy {
+            builder = builder.failure_policy(fp);
+        }
+        if let Some(ilp) = config.internal_log_policy {
+            builder = builder.internal_log_policy(ilp);
+        }
+        if let Some(level) = config.level {
+            let directives: Directives = level
+                .parse()
+                .map_err(|_| ConfigError::new("level", "invalid directives"))?;
+            builder = builder.directives(directives);
+        }
+        if let Some(compress) = config.compress {
+            #[cfg(feature = "compress")]
+            {
+                builder = builder.compress(compress);
+            }
+            #[cfg(not(feature = "compress"))]
+            if compress {
+                return Err(ConfigError::new("compress", "the compress feature is disabled"));
+            }
+        }
+        if let Some(formatter) = config.formatter {
+            builder = builder.formatter(formatter.build()?);
+        }
+        if let Some(tls) = config.tls {
+            #[cfg(feature = "tls")]
+            {
+                builder = builder.tls_config(Arc::new(tls.build()?));
+            }
+            #[cfg(not(feature = "tls"))]
+            {
+                let _ = tls;
+                return Err(ConfigError::new("tls", "the tls feature is disabled"));
+            }
+        }
+        if let Some(trace_context) = config.trace_context {
+            #[cfg(feature = "opentelemetry")]
+            {
+                builder = builder.trace_context(&trace_context.trace_id, &trace_context.span_id);
+            }
+            #[cfg(not(feature = "opentelemetry"))]
+            {
+                let _ = trace_context;
+                return Err(ConfigError::new(
+                    "trace_context",
+                    "the opentelemetry feature is disabled",
+                ));
+            }
+        }
+
+        Ok(builder)
+    }
+}
+
+impl FormatterConfig {
+    // Each arm returns on its own, as the match is empty when no formatter feature is enabled
+    fn build(self) -> Result<Box<dyn LokiFormatter>, ConfigError> {
+        match self {
+            #[cfg(feature = "logfmt")]
+            FormatterConfig::Logfmt {
+                fields,
+                escape_newlines,
+                render_line,
+                field_order,
+                field_names,
+                level_names,
+                #[cfg(feature = "kv")]
+                error_chain,
+            } => {
+                let formatter = LogfmtFormatter::new(auto_fields(fields)?, escape_newlines)
+                    .render_line(render_line)
+                    .field_order(field_order)
+                    .field_names(field_names)
+                    .level_names(level_names);
+                #[cfg(feature = "kv")]
+                let formatter = formatter.error_chain(error_chain);
+                Ok(Box::new(formatter))
+            },
+            #[cfg(feature = "json")]
+            FormatterConfig::Json {
+                fields,
+                field_names,
+                level_names,
+                #[cfg(feature = "kv")]
+                error_chain,
+            } => {
+                let formatter = JsonFormatter::new(auto_fields(fields)?)
+                    .field_names(field_names)
+                    .level_names(level_names);
+                #[cfg(feature = "kv")]
+                let formatter = formatter.error_chain(error_chain);
+                Ok(Box::new(formatter))
+            },
+            #[cfg(feature = "pattern")]
+            FormatterConfig::Pattern {
+                template,
+                #[cfg(feature = "kv")]
+                error_chain,
+            } => {
+                let formatter = PatternFormatter::new(&template)
+                    .map_err(|_| ConfigError::new("formatter.template", "invalid pattern"))?;
+                #[cfg(feature = "kv")]
+                let formatter = formatter.error_chain(error_chain);
+                Ok(Box::new(formatter))
+            },
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+impl TlsFiles {
+    fn build(&self) -> Result<TlsConfig, ConfigError> {
+        let mut tls_config = TlsConfig::builder();
+
+        if let Some(ca_file) = &self.ca_file {
+            let (certs, _) = read_pem("tls.ca_file", ca_file)?;
+            if certs.is_empty() {
+                return Err(ConfigError::new("tls.ca_file", "no certificates found"));
+            }
+            tls_config = tls_config.root_certs(RootCerts::new_with_certs(&certs));
+        }
+
+        match (&self.cert_file, &self.key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let (certs, _) = read_pem("tls.cert_file", cert_file)?;
+                if certs.is_empty() {
+                    return Err(ConfigError::new("tls.cert_file", "no certificates found"));
+                }
+                let key = read_pem("tls.key_file", key_file)?
+                    .1
+                    .ok_or(ConfigError::new("tls.key_file", "no private key found"))?;
+                tls_config = tls_config.client_cert(Some(ClientCert::new_with_certs(&certs, key)));
+            },
+            (None, None) => {},
+            (None, Some(_)) => return Err(ConfigError::new("tls.cert_file", "required with tls.key_file")),
+            (Some(_), None) => return Err(ConfigError::new("tls.key_file", "required with tls.cert_file")),
+        }
+
+        Ok(tls_config.build())
+    }
+}
+
+// Reads the certificates and the first private key from a PEM file
+#[cfg(feature = "tls")]
+fn read_pem(key: &str, path: &Path) -> Result<(Vec<Certificate<'static>>, Option<PrivateKey<'static>>), ConfigError> {
+    let pem = fs::read(path).map_err(|_| ConfigError::new(key, "cannot read file"))?;
+    let mut certs = Vec::new();
+    let mut private_key = None;
+
+    for item in parse_pem(&pem) {
+        match item.map_err(|_| ConfigError::new(key, "invalid PEM file"))? {
+            PemItem::Certificate(cert) => certs.push(cert),
+            PemItem::PrivateKey(pk) => {
+                private_key.get_or_insert(pk);
+            },
+            _ => {},
+        }
+    }
+
+    Ok((certs, private_key))
+}
+
+// Converts AutoFields flag names to AutoFields, or returns the default fields if there are none
+#[cfg(any(feature = "logfmt", feature = "json"))]
+fn auto_fields(names: Option<Vec<String>>) -> Result<AutoFields, ConfigError> {
+    match names {
+        None => Ok(AutoFields::default()),
+        Some(names) => names.iter().try_fold(AutoFields::empty(), |fields, name| {
+            AutoFields::from_name(&name.to_uppercase())
+                .map(|field| fields | field)
+                .ok_or(ConfigError::new("formatter.fields", "unknown field"))
+        }),
+    }
+}
+
+// Parses comma separated name=value pairs
+fn parse_pairs(var: &'static str, pairs: &str) -> Result<HashMap<String, String>, ConfigError> {
+    pairs
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((name, value)) if !name.trim().is_empty() => {
+                Ok((String::from(name.trim()), String::from(value.trim())))
+            },
+            _ => Err(ConfigError::new(var, "expected comma separated name=value pairs")),
+        })
+        .collect()
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Some(true),
+        "false" | "0" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+// Loki label names must match [a-zA-Z_][a-zA-Z0-9_]*
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Standard base64 with padding, as used by basic authentication
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_vars(vars: &[(&str, &str)]) -> Result<LokiBuilder, ConfigError> {
+        let vars: HashMap<_, _> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        LokiBuilder::from_vars(|var| Ok(vars.get(var).cloned()))
+    }
+
+    #[test]
+    fn builder_from_env_unreadable() {
+        let error = LokiBuilder::from_vars(|var| match var {
+            "LOKI_TENANT" => Err(ConfigError::new(var, "not valid unicode")),
+            "LOKI_ENDPOINT" => Ok(Some("http://localhost:3100".to_owned())),
+            "LOKI_LABELS" => Ok(Some("app=api".to_owned())),
+            _ => Ok(None),
+        })
+        .err()
+        .unwrap();
+        assert_eq!(error.key(), "LOKI_TENANT");
+    }
+
+    #[test]
+    fn builder_from_env() {
+        let builder = from_vars(&[
+            ("LOKI_ENDPOINT", "http://localhost:3100/loki/api/v1/push"),
+            ("LOKI_LABELS", "app=api, env=prod"),
+            ("LOKI_HEADERS", "X-Custom=1"),
+            ("LOKI_TENANT", "team-a"),
+            ("LOKI_USERNAME", "user"),
+            ("LOKI_PASSWORD", "pass"),
+            ("LOKI_LOG", "info,my_crate=debug"),
+            ("LOKI_BATCH_SIZE", "100"),
+            ("LOKI_BATCH_TIMEOUT", "2.5"),
+            ("LOKI_COMPRESS", "false"),
+        ])
+        .unwrap();
+
+        assert_eq!(builder.labels["env"], "prod");
+        assert_eq!(builder.headers["X-Custom"], "1");
+        assert_eq!(builder.headers["X-Scope-OrgID"], "team-a");
+        assert_eq!(builder.headers["Authorization"], "Basic dXNlcjpwYXNz");
+        assert_eq!(builder.directives, "info,my_crate=debug".parse().unwrap());
+        assert_eq!(builder.max_log_lines, 100);
+        assert_eq!(builder.max_log_lifetime, Duration::from_millis(2500));
+
+        let error = |vars: &[(&str, &str)]| from_vars(vars).err().map(|err| err.key().to_owned());
+        let endpoint = ("LOKI_ENDPOINT", "http://localhost:3100");
+        let labels = ("LOKI_LABELS", "app=api");
+        assert_eq!(error(&[labels]).as_deref(), Some("LOKI_ENDPOINT"));
+        assert_eq!(error(&[endpoint]).as_deref(), Some("LOKI_LABELS"));
+        assert_eq!(
+            error(&[endpoint, ("LOKI_LABELS", "1app=api")]).as_deref(),
+            Some("LOKI_LABELS")
+        );
+        assert_eq!(
+            error(&[endpoint, ("LOKI_LABELS", "app")]).as_deref(),
+            Some("LOKI_LABELS")
+        );
+        assert_eq!(
+            error(&[endpoint, labels, ("LOKI_BATCH_SIZE", "0")]).as_deref(),
+            Some("LOKI_BATCH_SIZE")
+        );
+        assert_eq!(
+            error(&[endpoint, labels, ("LOKI_PASSWORD", "x")]).as_deref(),
+            Some("LOKI_USERNAME")
+        );
+        assert_eq!(
+            error(&[endpoint, labels, ("LOKI_COMPRESS", "maybe")]).as_deref(),
+            Some("LOKI_COMPRESS")
+        );
+        assert_eq!(
+            error(&[endpoint, labels, ("LOKI_HEADERS", "a b=1")]).as_deref(),
+            Some("LOKI_HEADERS")
+        );
+        assert_eq!(error(&[endpoint, labels]), None);
+    }
+
+    #[test]
+    fn base64_encode() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[cfg(all(feature = "json", feature = "tls"))]
+    #[test]
+    fn builder_from_config() {
+        let config: LokiConfig = serde_json::from_str(
+            r#"{
+                "endpoint": "http://localhost:3100/loki/api/v1/push",
+                "labels": {"app": "api"},
+                "headers": {"X-Scope-OrgID": "team-a"},
+                "max_log_lifetime": 1.5,
+                "multiline": {"split": "entry_id"},
+                "failure_policy": {"retry": 3},
+                "internal_log_policy": "stderr",
+                "level": "warn",
+                "formatter": {"type": "json", "fields": ["level", "target"], "level_names": {"warn": "warning"}}
+            }"#,
+        )
+        .unwrap();
+        let builder = LokiBuilder::from_config(config).unwrap();
+
+        assert_eq!(builder.headers["X-Scope-OrgID"], "team-a");
+        assert_eq!(builder.max_log_lifetime, Duration::from_millis(1500));
+        assert_eq!(builder.multiline, MultilineStrategy::Split("entry_id".to_owned()));
+        assert_eq!(builder.failure_policy, FailurePolicy::Retry(3));
+        assert_eq!(builder.internal_log_policy, InternalLogPolicy::Stderr);
+        assert_eq!(builder.directives, Directives::new(log::LevelFilter::Warn));
+
+        let record = log::Record::builder()
+            .args(format_args!("m"))
+            .level(log::Level::Warn)
+            .target("t")
+            .build();
+        assert_eq!(
+            builder.formatter.unwrap().log_line(&record).unwrap(),
+            r#"{"level":"warning","message":"m","target":"t"}"#
+        );
+
+        let error = |json: &str| {
+            let config: LokiConfig = serde_json::from_str(json).unwrap();
+            LokiBuilder::from_config(config).err().map(|err| err.key().to_owned())
+        };
+        assert_eq!(
+            error(
+                r#"{"endpoint": "http://l", "labels": {"app": "a"}, "formatter": {"type": "json", "fields": ["nope"]}}"#
+            )
+            .as_deref(),
+            Some("formatter.fields")
+        );
+        assert_eq!(
+            error(r#"{"endpoint": "http://l", "labels": {"app": "a"}, "tls": {"ca_file": "/nonexistent"}}"#).as_deref(),
+            Some("tls.ca_file")
+        );
+        assert!(serde_json::from_str::<LokiConfig>(r#"{"endpoint": "http://l", "labels": {}, "typo": 1}"#).is_err());
+
+        let trace_context =
+            r#"{"endpoint": "http://l", "labels": {"app": "a"}, "trace_context": {"trace_id": "t", "span_id": "s"}}"#;
+        #[cfg(feature = "opentelemetry")]
+        assert_eq!(error(trace_context), None);
+        #[cfg(not(feature = "opentelemetry"))]
+        assert_eq!(error(trace_context).as_deref(), Some("trace_context"));
+
+        #[cfg(feature = "kv")]
+        assert_eq!(
+            error(
+                r#"{"endpoint": "http://l", "labels": {"app": "a"}, "formatter": {"type": "json", "error_chain": "joined"}}"#
+            ),
+            None
+        );
+    }
+}
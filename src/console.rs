@@ -0,0 +1,310 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use std::borrow::Cow;
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError, set_boxed_logger, set_max_level};
+
+#[cfg(feature = "redact")]
+use crate::Redactor;
+use crate::fmt::rfc3339;
+use crate::record::Prepared;
+#[cfg(feature = "kv")]
+use crate::{Attributes, context, kv_to_string};
+use crate::{Directives, FormatLog, Loki, LokiFormatter};
+
+/// `ConsoleLogger` writes logs to stderr, stdout or any other writer. It has its own directives
+/// and formatter, and is meant to be combined with `Loki` through a `TeeLogger`. The log line
+/// produced by the formatter is followed by its attributes as `key=value` pairs. Fields from the
+/// `context` module are passed to the formatter like the record's own structured fields.
+///
+/// By default, everything is written to stderr in a human readable format like
+/// `2024-01-01T00:00:00.000Z INFO  my_crate: message`, followed by the structured fields.
+pub struct ConsoleLogger {
+    directives: Directives,
+    fmt: Box<dyn LokiFormatter>,
+    output: Output,
+    #[cfg(feature = "redact")]
+    redactor: Option<Redactor>,
+}
+
+enum Output {
+    Stdout,
+    Stderr,
+    Writer(Mutex<Box<dyn Write + Send>>),
+}
+
+impl ConsoleLogger {
+    /// Create a logger that writes to stderr.
+    pub fn stderr() -> Self {
+        ConsoleLogger::with_output(Output::Stderr)
+    }
+
+    /// Create a logger that writes to stdout.
+    pub fn stdout() -> Self {
+        ConsoleLogger::with_output(Output::Stdout)
+    }
+
+    /// Create a logger that writes to the given writer, e.g. a file.
+    pub fn writer(writer: impl Write + Send + 'static) -> Self {
+        ConsoleLogger::with_output(Output::Writer(Mutex::new(Box::new(writer))))
+    }
+
+    fn with_output(output: Output) -> Self {
+        ConsoleLogger {
+            directives: Directives::default(),
+            fmt: Box::new(ConsoleFormatter),
+            output,
+            #[cfg(feature = "redact")]
+            redactor: None,
+        }
+    }
+
+    /// Sets the verbosity of this logger. Targets matched by a directive keep their own level.
+    pub fn level(mut self, lf: LevelFilter) -> Self {
+        self.directives = self.directives.default_level(lf);
+        self
+    }
+
+    /// Sets per-target verbosity using env_logger style directives.
+    pub fn directives(mut self, directives: Directives) -> Self {
+        self.directives = directives;
+        self
+    }
+
+    pub fn formatter(mut self, fmt: Box<dyn LokiFormatter>) -> Self {
+        self.fmt = fmt;
+        self
+    }
+
+    #[cfg(feature = "redact")]
+    /// Redact secrets from the message and structured fields of each record before it is
+    /// formatted. A `TeeLogger` uses the redactor of its `Loki` logger if none is set.
+    pub fn redactor(mut self, redactor: Redactor) -> Self {
+        self.redactor = Some(redactor);
+        self
+    }
+
+    fn write(&self, record: &dyn FormatLog) -> io::Result<()> {
+        #[allow(unused_mut)]
+        let mut record = Prepared::new(record);
+        #[cfg(feature = "kv")]
+        context::add_to(&mut record);
+        #[cfg(feature = "redact")]
+        if let Some(redactor) = &self.redactor {
+            record.redact(redactor);
+        }
+
+        let mut line = match self.fmt.log_line(&record) {
+            Ok(line) => line.into_owned(),
+            Err(_) => return Ok(()),
+        };
+        #[allow(unused_mut)]
+        let mut attributes = self.fmt.ordered_attributes(&record);
+        #[cfg(feature = "redact")]
+        if let Some(redactor) = &self.redactor {
+            record.redact_attributes(redactor, &mut attributes);
+        }
+        for (k, v) in attributes.iter() {
+            write!(line, " {k}={v}").expect("Writing to a String can't fail.");
+        }
+        line.push('\n');
+
+        match &self.output {
+            Output::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            Output::Stderr => io::stderr().lock().write_all(line.as_bytes()),
+            Output::Writer(writer) => writer.lock().unwrap().write_all(line.as_bytes()),
+        }
+    }
+}
+
+impl Log for ConsoleLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.directives.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            // There is nowhere to report a failing console
+            let _ = self.write(record);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = match &self.output {
+            Output::Stdout => io::stdout().flush(),
+            Output::Stderr => io::stderr().flush(),
+            Output::Writer(writer) => writer.lock().unwrap().flush(),
+        };
+    }
+}
+
+// The default console format
+struct ConsoleFormatter;
+
+impl LokiFormatter for ConsoleFormatter {
+    fn log_line<'a>(&self, rec: &'a dyn FormatLog) -> Result<Cow<'a, str>, fmt::Error> {
+        Ok(Cow::Owned(format!(
+            "{} {:<5} {}: {}",
+            rfc3339(SystemTime::now()),
+            rec.level().to_uppercase(),
+            rec.target(),
+            rec.message()
+        )))
+    }
+
+    #[cfg(feature = "kv")]
+    fn ordered_attributes(&self, rec: &dyn FormatLog) -> Attributes {
+        let mut attributes = Attributes::new();
+        let _ = rec.key_values().visit(&mut FieldCollector(&mut attributes));
+        attributes
+    }
+}
+
+// Collects structured fields as text
+#[cfg(feature = "kv")]
+struct FieldCollector<'a>(&'a mut Attributes);
+
+#[cfg(feature = "kv")]
+impl<'kvs> log::kv::VisitSource<'kvs> for FieldCollector<'_> {
+    fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+        self.0.insert(key.to_string(), kv_to_string(&value));
+        Ok(())
+    }
+}
+
+/// `TeeLogger` sends every record both to `Loki` and to a `ConsoleLogger`, each of which applies
+/// its own directives and formatter. This replaces the need for a logging framework like fern
+/// when logs should end up both in Loki and on the console.
+pub struct TeeLogger {
+    loki: Loki,
+    console: ConsoleLogger,
+}
+
+impl TeeLogger {
+    /// Create a logger that writes to both loggers. If the console has no redactor, it uses the
+    /// redactor of `loki`, so secrets don't reach the console either.
+    #[allow(unused_mut)]
+    pub fn new(loki: Loki, mut console: ConsoleLogger) -> Self {
+        #[cfg(feature = "redact")]
+        if console.redactor.is_none() {
+            console.redactor = loki.redactor.clone();
+        }
+        TeeLogger { loki, console }
+    }
+
+    /// Installs the logger as the default logger for the entire program.
+    /// Calling this (or any similar function from other libraries) more than once is a bug.
+    ///
+    /// The global maximum level is set to the most verbose level of the two loggers. It keeps
+    /// following the level of `Loki` when it is changed through its `LevelHandle`.
+    pub fn apply(self) -> Result<(), SetLoggerError> {
+        let levels = self.loki.level_handle();
+        levels
+            .floor
+            .store(self.console.directives.max_level() as usize, Ordering::Release);
+        let directives = levels.directives.read().unwrap();
+
+        set_boxed_logger(Box::new(self))?;
+        set_max_level(levels.max_level(&directives));
+        levels.installed.store(true, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl Log for TeeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.loki.enabled(metadata) || self.console.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.console.log(record);
+        self.loki.log(record);
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        self.loki.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn console_write_record() {
+        let buffer = Buffer::default();
+        let console = ConsoleLogger::writer(buffer.clone()).directives("info,noisy=error".parse().unwrap());
+
+        let record = |target, level| {
+            console.log(
+                &Record::builder()
+                    .args(format_args!("message"))
+                    .level(level)
+                    .target(target)
+                    .build(),
+            );
+        };
+        record("my_crate", log::Level::Info);
+        record("my_crate", log::Level::Debug);
+        record("noisy", log::Level::Warn);
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(output.lines().count(), 1);
+        assert!(output.ends_with("Z INFO  my_crate: message\n"), "{output}");
+    }
+
+    #[cfg(feature = "kv")]
+    #[test]
+    fn console_context_fields() {
+        let buffer = Buffer::default();
+        let console = ConsoleLogger::writer(buffer.clone());
+
+        context::scope([("request_id", "r1")], || {
+            console.log(&Record::builder().args(format_args!("message")).build());
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(output.ends_with(": message request_id=r1\n"), "{output}");
+    }
+
+    #[cfg(feature = "redact")]
+    #[test]
+    fn tee_redacts_console() {
+        let buffer = Buffer::default();
+        let (loki, captured) = Loki::capture(crate::test_builder().redactor(Redactor::with_defaults()));
+        let tee = TeeLogger::new(loki, ConsoleLogger::writer(buffer.clone()));
+
+        tee.log(&Record::builder().args(format_args!("mail ann@example.com")).build());
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(output.ends_with(": mail [REDACTED]\n"), "{output}");
+        assert_eq!(captured.logs()[0].0, "mail [REDACTED]");
+    }
+}
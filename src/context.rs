@@ -0,0 +1,163 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+//! Request scoped fields, similar to log4j's MDC.
+//!
+//! Fields entered with [`scope`] are attached to every log sent to Loki or written by a
+//! `ConsoleLogger` from within the scope on the same thread. [`scope_future`] does the same for
+//! everything logged while a future is polled, so fields follow a task across threads of an async
+//! runtime. Scopes can be nested; for fields with the same key, the innermost scope wins. The
+//! fields of the record itself take precedence over fields from the context.
+//!
+//! The fields are passed to the formatter as structured fields, like the key-value pairs of the
+//! record. Values such as request IDs are unique to a request, so they should be part of the log
+//! line rather than labels. Use a formatter that writes fields into the line, such as
+//! `JsonFormatter` or `LogfmtFormatter::render_line(true)`. The default `LogfmtFormatter` returns
+//! fields as attributes, which become stream labels with the `multistream` feature.
+//!
+//! ```ignore
+//! log_loki::context::scope([("request_id", id)], || {
+//!     info!("handling request");
+//! });
+//! ```
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::Attributes;
+use crate::record::Prepared;
+
+thread_local! {
+    // The fields of all scopes entered on this thread, outermost first
+    static FIELDS: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Run `f` with `fields` attached to every log emitted on this thread in the meantime.
+pub fn scope<K, V, R>(fields: impl IntoIterator<Item = (K, V)>, f: impl FnOnce() -> R) -> R
+where
+    K: Into<String>,
+    V: Into<String>,
+{
+    let fields: Vec<_> = fields.into_iter().map(|(k, v)| (k.into(), v.into())).collect();
+    let _guard = ScopeGuard::enter(&fields);
+    f()
+}
+
+/// Wrap `future` so that `fields` are attached to every log emitted while it is polled.
+pub fn scope_future<K, V, F>(fields: impl IntoIterator<Item = (K, V)>, future: F) -> Scoped<F>
+where
+    K: Into<String>,
+    V: Into<String>,
+    F: Future,
+{
+    Scoped {
+        fields: fields.into_iter().map(|(k, v)| (k.into(), v.into())).collect(),
+        future: Box::pin(future),
+    }
+}
+
+/// Returns the fields of all scopes the current thread is in.
+pub fn current() -> Attributes {
+    FIELDS.with_borrow(|fields| fields.iter().cloned().collect())
+}
+
+/// A future returned by [`scope_future`].
+pub struct Scoped<F> {
+    fields: Vec<(String, String)>,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let _guard = ScopeGuard::enter(&this.fields);
+        this.future.as_mut().poll(cx)
+    }
+}
+
+// Adds fields to the thread's context and removes them when dropped, even if the scope panics
+struct ScopeGuard(usize);
+
+impl ScopeGuard {
+    fn enter(fields: &[(String, String)]) -> Self {
+        FIELDS.with_borrow_mut(|current| {
+            let len = current.len();
+            current.extend_from_slice(fields);
+            ScopeGuard(len)
+        })
+    }
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        FIELDS.with_borrow_mut(|current| current.truncate(self.0));
+    }
+}
+
+// Adds the context's fields to a record, unless it already has them
+pub(crate) fn add_to(record: &mut Prepared) {
+    FIELDS.with_borrow(|fields| {
+        // Innermost first, so inner scopes win
+        for (key, value) in fields.iter().rev() {
+            record.add_field(key, value.clone());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+
+    use log::kv::{Key, Value};
+
+    use super::*;
+    use crate::FormatLog;
+
+    #[test]
+    fn nested_scopes() {
+        scope([("request_id", "1"), ("user", "ann")], || {
+            scope([("user", "bob")], || {
+                let kvs: &[(&str, Value)] = &[("request_id", "own".into())];
+                let record = log::Record::builder().key_values(&kvs).build();
+                let mut prepared = Prepared::new(&record);
+                add_to(&mut prepared);
+
+                let field = |key| prepared.key_values().get(Key::from_str(key)).map(|v| v.to_string());
+                assert_eq!(field("request_id").as_deref(), Some("own"));
+                assert_eq!(field("user").as_deref(), Some("bob"));
+            });
+            assert_eq!(current().get("user"), Some("ann"));
+        });
+        assert!(current().is_empty());
+
+        let result = std::panic::catch_unwind(|| scope([("a", "1")], || panic!()));
+        assert!(result.is_err());
+        assert!(current().is_empty());
+    }
+
+    #[test]
+    fn scoped_future() {
+        struct NoopWaker;
+
+        impl Wake for NoopWaker {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        let mut future = scope_future([("request_id", "2")], async { current() });
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let poll = Pin::new(&mut future).poll(&mut Context::from_waker(&waker));
+
+        assert!(matches!(poll, Poll::Ready(fields) if fields.get("request_id") == Some("2")));
+        assert!(current().is_empty());
+    }
+}
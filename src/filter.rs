@@ -0,0 +1,248 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::{env, fmt};
+
+use log::{LevelFilter, Metadata, set_max_level};
+
+/// `Directives` decide which records are sent to Loki based on their target. They use the same
+/// syntax as env_logger's `RUST_LOG` variable, e.g. `info,my_crate=debug,ureq=off`.
+///
+/// A record is matched against the directive with the longest target that is a prefix of the
+/// record's target. Records that match no directive use the default level.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Directives {
+    default: LevelFilter,
+    // Sorted by ascending target length so that the last match is the most specific one
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Directives {
+    /// Create a new set of directives that enables everything up to `default`.
+    pub fn new(default: LevelFilter) -> Self {
+        Directives {
+            default,
+            targets: Vec::new(),
+        }
+    }
+
+    /// Read directives from the environment variable `var`. Returns `Ok(None)` if the variable is
+    /// not set.
+    pub fn from_env(var: &str) -> Result<Option<Self>, ParseDirectivesError> {
+        match env::var(var) {
+            Ok(spec) => spec.parse().map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Set the level used for records that match no target directive.
+    pub fn default_level(mut self, level: LevelFilter) -> Self {
+        self.default = level;
+        self
+    }
+
+    /// Set the level for all records whose target starts with `target`. Replaces any previous
+    /// directive for the same target.
+    pub fn target(mut self, target: &str, level: LevelFilter) -> Self {
+        self.targets.retain(|(t, _)| t != target);
+        let pos = self.targets.partition_point(|(t, _)| t.len() <= target.len());
+        self.targets.insert(pos, (String::from(target), level));
+        self
+    }
+
+    /// Returns the level that applies to records with the given target.
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .rev()
+            .find(|(t, _)| target.starts_with(t.as_str()))
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// Returns true if a record with the given metadata should be logged.
+    pub fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    /// Returns the most verbose level enabled by any directive. This is suitable for passing to
+    /// `log::set_max_level`.
+    pub fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+impl Default for Directives {
+    fn default() -> Self {
+        Directives::new(LevelFilter::Trace)
+    }
+}
+
+impl FromStr for Directives {
+    type Err = ParseDirectivesError;
+
+    /// Parse a comma separated list of directives. Each directive is either a bare level, which
+    /// sets the default level, a bare target, which enables all levels for that target, or a
+    /// `target=level` pair. As with env_logger, everything is disabled by default unless a bare
+    /// level is given. Regex message filters (`/filter`) are not supported.
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        if spec.contains('/') {
+            return Err(ParseDirectivesError::new(spec, "message filters are not supported"));
+        }
+
+        let mut directives = Directives::new(LevelFilter::Off);
+
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let target = target.trim();
+                    if target.is_empty() {
+                        return Err(ParseDirectivesError::new(directive, "missing target"));
+                    }
+                    let level = level
+                        .trim()
+                        .parse()
+                        .map_err(|_| ParseDirectivesError::new(directive, "invalid level"))?;
+                    directives = directives.target(target, level);
+                },
+                None => match directive.parse() {
+                    Ok(level) => directives.default = level,
+                    Err(_) => directives = directives.target(directive, LevelFilter::Trace),
+                },
+            }
+        }
+
+        Ok(directives)
+    }
+}
+
+/// `LevelHandle` changes the verbosity of a `Loki` logger at runtime, including after it has been
+/// installed with `.apply()`. Obtain one with `Loki::level_handle()` before installing the logger.
+#[derive(Debug, Clone)]
+pub struct LevelHandle {
+    pub(crate) directives: Arc<RwLock<Directives>>,
+    pub(crate) installed: Arc<AtomicBool>,
+    // The most verbose level of another logger installed alongside, such as a ConsoleLogger in a
+    // TeeLogger. The global maximum level never drops below it.
+    pub(crate) floor: Arc<AtomicUsize>,
+}
+
+impl LevelHandle {
+    /// Returns a copy of the directives currently in effect.
+    pub fn directives(&self) -> Directives {
+        self.directives.read().unwrap().clone()
+    }
+
+    /// Replaces all directives. If the logger was installed with `.apply()`, the global maximum
+    /// level is updated to match.
+    pub fn set_directives(&self, directives: Directives) {
+        self.update(|current| *current = directives);
+    }
+
+    /// Changes the default level while keeping any per-target directives.
+    pub fn set_level(&self, level: LevelFilter) {
+        self.update(|current| current.default = level);
+    }
+
+    pub(crate) fn new(directives: Directives) -> Self {
+        LevelHandle {
+            directives: Arc::new(RwLock::new(directives)),
+            installed: Arc::new(AtomicBool::new(false)),
+            floor: Arc::new(AtomicUsize::new(LevelFilter::Off as usize)),
+        }
+    }
+
+    // Returns the global maximum level for the given directives
+    pub(crate) fn max_level(&self, directives: &Directives) -> LevelFilter {
+        let floor = LevelFilter::iter()
+            .nth(self.floor.load(Ordering::Acquire))
+            .unwrap_or(LevelFilter::Trace);
+        directives.max_level().max(floor)
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Directives)) {
+        let mut current = self.directives.write().unwrap();
+        f(&mut current);
+
+        // Update while holding the lock so concurrent changes can't leave a stale max level behind
+        if self.installed.load(Ordering::Acquire) {
+            set_max_level(self.max_level(&current));
+        }
+    }
+}
+
+/// Returned when a directive string cannot be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseDirectivesError {
+    directive: String,
+    reason: &'static str,
+}
+
+impl ParseDirectivesError {
+    fn new(directive: &str, reason: &'static str) -> Self {
+        ParseDirectivesError {
+            directive: String::from(directive),
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for ParseDirectivesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid log directive '{}': {}", self.directive, self.reason)
+    }
+}
+
+impl Error for ParseDirectivesError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directives_parse() {
+        let directives: Directives = "info,my_crate=debug,my_crate::noisy=warn,ureq=off".parse().unwrap();
+
+        assert_eq!(directives.level_for("other"), LevelFilter::Info);
+        assert_eq!(directives.level_for("my_crate"), LevelFilter::Debug);
+        assert_eq!(directives.level_for("my_crate::net"), LevelFilter::Debug);
+        assert_eq!(directives.level_for("my_crate::noisy::inner"), LevelFilter::Warn);
+        assert_eq!(directives.level_for("ureq::pool"), LevelFilter::Off);
+        assert_eq!(directives.max_level(), LevelFilter::Debug);
+
+        let directives: Directives = "my_crate".parse().unwrap();
+        assert_eq!(directives.level_for("other"), LevelFilter::Off);
+        assert_eq!(directives.level_for("my_crate"), LevelFilter::Trace);
+
+        assert!("my_crate=loud".parse::<Directives>().is_err());
+        assert!("=info".parse::<Directives>().is_err());
+        assert!("info/foo".parse::<Directives>().is_err());
+    }
+
+    #[test]
+    fn level_handle_updates() {
+        let handle = LevelHandle::new("info,my_crate=debug".parse().unwrap());
+
+        handle.set_level(LevelFilter::Warn);
+        assert_eq!(handle.directives().level_for("other"), LevelFilter::Warn);
+        assert_eq!(handle.directives().level_for("my_crate"), LevelFilter::Debug);
+
+        handle.set_directives(Directives::new(LevelFilter::Error));
+        assert_eq!(handle.directives().level_for("my_crate"), LevelFilter::Error);
+
+        assert_eq!(handle.max_level(&handle.directives()), LevelFilter::Error);
+        handle.floor.store(LevelFilter::Debug as usize, Ordering::Release);
+        assert_eq!(handle.max_level(&handle.directives()), LevelFilter::Debug);
+    }
+}
@@ -9,6 +9,13 @@ file, You can obtain one at http://mozilla.org/MPL/2.0/.
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt;
+use std::thread::{self, ThreadId};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitflags::bitflags;
+use log::Level;
+use serde::Deserialize;
+use serde::ser::{Serialize, SerializeMap, Serializer};
 
 pub trait FormatLog {
     fn level(&self) -> Cow<'_, str>;
@@ -17,7 +24,7 @@ pub trait FormatLog {
     fn module(&self) -> Option<Cow<'_, str>>;
     fn file(&self) -> Option<Cow<'_, str>>;
     fn line(&self) -> Option<Cow<'_, str>>;
-    #[cfg(feature = "kv_unstable")]
+    #[cfg(feature = "kv")]
     fn key_values(&self) -> &dyn log::kv::Source;
 }
 
@@ -50,7 +57,7 @@ impl FormatLog for log::Record<'_> {
         self.line().map(|line| Cow::Owned(line.to_string()))
     }
 
-    #[cfg(feature = "kv_unstable")]
+    #[cfg(feature = "kv")]
     fn key_values(&self) -> &dyn log::kv::Source {
         self.key_values()
     }
@@ -67,4 +74,558 @@ pub trait LokiFormatter: Send + Sync {
     fn attributes(&self, _rec: &dyn FormatLog) -> HashMap<String, String> {
         Default::default()
     }
+
+    /// The attributes of a record, in the order they are sent to Loki. This is what the logger
+    /// uses; by default it collects the pairs returned by `attributes`, in no particular order.
+    fn ordered_attributes(&self, rec: &dyn FormatLog) -> Attributes {
+        self.attributes(rec).into_iter().collect()
+    }
+}
+
+/// `Attributes` is an ordered set of key value pairs produced by a `LokiFormatter`. The order in
+/// which the pairs are kept is the order in which they are sent to Loki.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attributes(Vec<(String, String)>);
+
+impl Attributes {
+    pub fn new() -> Self {
+        Attributes(Vec::new())
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Attributes(Vec::with_capacity(capacity))
+    }
+
+    /// Insert a key value pair. If the key is already present, its value is replaced in place and
+    /// the old value is returned.
+    pub fn insert(&mut self, key: String, value: String) -> Option<String> {
+        match self.0.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => Some(std::mem::replace(v, value)),
+            None => {
+                self.0.push((key, value));
+                None
+            },
+        }
+    }
+
+    /// Remove a key and return its value, if it was present.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let pos = self.0.iter().position(|(k, _)| k == key)?;
+        Some(self.0.remove(pos).1)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    // Keeps the pairs for which f returns true, with mutable access to the values
+    #[cfg(feature = "redact")]
+    pub(crate) fn retain_mut(&mut self, mut f: impl FnMut(&str, &mut String) -> bool) {
+        self.0.retain_mut(|(k, v)| f(k, v));
+    }
+
+    /// Iterate over the pairs with mutable access to the values.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&str, &mut String)> {
+        self.0.iter_mut().map(|(k, v)| (k.as_str(), v))
+    }
+
+    /// Reorder the pairs according to `order`.
+    pub fn sort(&mut self, order: &FieldOrder) {
+        match order {
+            FieldOrder::Insertion => {},
+            FieldOrder::Sorted => self.0.sort_by(|(a, _), (b, _)| a.cmp(b)),
+            FieldOrder::Explicit(keys) => {
+                // Keys missing from the explicit order go last, keeping their relative order
+                self.0
+                    .sort_by_key(|(k, _)| keys.iter().position(|key| key == k).unwrap_or(keys.len()));
+            },
+        }
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Attributes {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut attributes = Attributes::new();
+        attributes.extend(iter);
+        attributes
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> Extend<(K, V)> for Attributes {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (k, v) in iter {
+            self.insert(k.into(), v.into());
+        }
+    }
+}
+
+impl IntoIterator for Attributes {
+    type Item = (String, String);
+    type IntoIter = std::vec::IntoIter<(String, String)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl Serialize for Attributes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (k, v) in &self.0 {
+            map.serialize_entry(k, v)?;
+        }
+        map.end()
+    }
+}
+
+/// `FieldOrder` determines the order of the fields written by the built-in formatters.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldOrder {
+    /// Fields are written in the order they were produced: automatic fields first, then
+    /// structured fields in the order they were given.
+    #[default]
+    Insertion,
+    /// Fields are sorted by key
+    Sorted,
+    /// The given keys are written first, in the given order. Any other fields follow in the order
+    /// they were produced.
+    Explicit(Vec<String>),
+}
+
+bitflags! {
+    /// `AutoFields` is used to determine what fields of a log::Record should be rendered by the
+    /// built-in formatters, such as the `LogfmtFormatter`. The default set is LEVEL | MODULE_PATH
+    /// | EXTRA
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct AutoFields: u32 {
+        /// Include a `level` field indicating the level the message was logged at.
+        const LEVEL = 1;
+        /// Include the `message` field containing the message passed to the log directive
+        const MESSAGE = 1 << 1;
+        /// Include a `target` field, corresponding to the target of the log directive
+        const TARGET = 1 << 2;
+        /// Include the `module` field set on the log record
+        const MODULE_PATH = 1 << 3;
+        /// Include the `file` field set on the log record
+        const FILE = 1 << 4;
+        /// Include the `line` field associated with the log directive.
+        const LINE = 1 << 5;
+        /// Include any extra fields specified via the structured logging API, if enabled.
+        #[cfg(feature = "kv")]
+        const EXTRA = 1 << 6;
+        /// Include a `caller` field combining the file and line, e.g. `src/main.rs:12`
+        const CALLER = 1 << 7;
+        /// Include a `thread` field with the name of the thread that logged the record, or its ID
+        /// if it has no name. See `TIMESTAMP` for when the `LogfmtFormatter` leaves it out.
+        const THREAD = 1 << 8;
+        /// Include a `thread_id` field with the ID of the thread that logged the record. See
+        /// `TIMESTAMP` for when the `LogfmtFormatter` leaves it out.
+        const THREAD_ID = 1 << 9;
+        /// Include a `timestamp` field with the time the record was logged, in RFC 3339 format.
+        /// Unlike the timestamp Loki stores, this is visible in the log line itself. With the
+        /// `multistream` feature and without `render_line`, the `LogfmtFormatter` leaves this
+        /// field out, as the attribute would become a stream label with a new value for almost
+        /// every record.
+        const TIMESTAMP = 1 << 10;
+        /// Include a `pid` field with the ID of the current process
+        const PID = 1 << 11;
+    }
+}
+
+impl Default for AutoFields {
+    fn default() -> Self {
+        #[cfg(feature = "kv")]
+        {
+            AutoFields::LEVEL | AutoFields::MODULE_PATH | AutoFields::EXTRA
+        }
+
+        #[cfg(not(feature = "kv"))]
+        {
+            AutoFields::LEVEL | AutoFields::MODULE_PATH
+        }
+    }
+}
+
+/// `FieldNames` specifies the keys that the built-in formatters use for the fields selected by
+/// `AutoFields`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct FieldNames {
+    pub level: String,
+    pub message: String,
+    pub target: String,
+    pub module: String,
+    pub file: String,
+    pub line: String,
+    pub caller: String,
+    pub thread: String,
+    pub thread_id: String,
+    pub timestamp: String,
+    pub pid: String,
+}
+
+impl Default for FieldNames {
+    fn default() -> Self {
+        FieldNames {
+            level: "level".to_owned(),
+            message: "message".to_owned(),
+            target: "target".to_owned(),
+            module: "module".to_owned(),
+            file: "file".to_owned(),
+            line: "line".to_owned(),
+            caller: "caller".to_owned(),
+            thread: "thread".to_owned(),
+            thread_id: "thread_id".to_owned(),
+            timestamp: "timestamp".to_owned(),
+            pid: "pid".to_owned(),
+        }
+    }
+}
+
+/// `LevelNames` specifies how the built-in formatters spell each log level.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct LevelNames {
+    pub error: String,
+    pub warn: String,
+    pub info: String,
+    pub debug: String,
+    pub trace: String,
+}
+
+impl LevelNames {
+    /// Returns the spelling for a level name as returned by `FormatLog::level`. Names that are not
+    /// a known level are returned unchanged.
+    pub fn get<'a>(&'a self, level: &'a str) -> &'a str {
+        match level.parse() {
+            Ok(Level::Error) => &self.error,
+            Ok(Level::Warn) => &self.warn,
+            Ok(Level::Info) => &self.info,
+            Ok(Level::Debug) => &self.debug,
+            Ok(Level::Trace) => &self.trace,
+            Err(_) => level,
+        }
+    }
+}
+
+impl Default for LevelNames {
+    fn default() -> Self {
+        LevelNames {
+            error: "error".to_owned(),
+            warn: "warn".to_owned(),
+            info: "info".to_owned(),
+            debug: "debug".to_owned(),
+            trace: "trace".to_owned(),
+        }
+    }
+}
+
+/// Convert a structured value to JSON. Primitives and values captured with serde keep their type,
+/// anything else is converted to a string.
+#[cfg(feature = "kv")]
+pub fn kv_to_json(value: &log::kv::Value) -> serde_json::Value {
+    // NaN and infinities can't be represented in JSON
+    if value.to_f64().is_some_and(|f| !f.is_finite()) {
+        return serde_json::Value::String(value.to_string());
+    }
+    serde_json::to_value(value).unwrap_or_else(|_| serde_json::Value::String(value.to_string()))
+}
+
+/// Convert a structured value to a string for text based formats. Strings are returned as is,
+/// while other values, such as numbers, booleans and values captured with serde, are rendered as
+/// JSON.
+#[cfg(feature = "kv")]
+pub fn kv_to_string(value: &log::kv::Value) -> String {
+    match kv_to_json(value) {
+        serde_json::Value::String(s) => s,
+        json => json.to_string(),
+    }
+}
+
+// Structured fields captured from other logging frameworks, keeping their types. Recording a key
+// that is already present replaces its value in place.
+#[cfg(any(feature = "tracing", feature = "slog"))]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct OwnedFields(Vec<(Cow<'static, str>, OwnedValue)>);
+
+#[cfg(any(feature = "tracing", feature = "slog"))]
+impl OwnedFields {
+    pub(crate) fn insert(&mut self, key: impl Into<Cow<'static, str>>, value: OwnedValue) {
+        let key = key.into();
+        match self.0.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.0.push((key, value)),
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    pub(crate) fn extend(&mut self, other: &OwnedFields) {
+        for (key, value) in &other.0 {
+            self.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+#[cfg(any(feature = "tracing", feature = "slog"))]
+impl log::kv::Source for OwnedFields {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn log::kv::VisitSource<'kvs>) -> Result<(), log::kv::Error> {
+        for (key, value) in &self.0 {
+            visitor.visit_pair(log::kv::Key::from_str(key), value.to_value())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(feature = "tracing", feature = "slog"))]
+#[derive(Debug, Clone)]
+pub(crate) enum OwnedValue {
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+    Str(String),
+}
+
+#[cfg(any(feature = "tracing", feature = "slog"))]
+impl OwnedValue {
+    fn to_value(&self) -> log::kv::Value<'_> {
+        match self {
+            OwnedValue::I64(v) => log::kv::Value::from(*v),
+            OwnedValue::U64(v) => log::kv::Value::from(*v),
+            OwnedValue::F64(v) => log::kv::Value::from(*v),
+            OwnedValue::Bool(v) => log::kv::Value::from(*v),
+            OwnedValue::Str(v) => log::kv::Value::from(v.as_str()),
+        }
+    }
+}
+
+/// `ErrorChain` specifies how the built-in formatters render structured values that are errors,
+/// such as those logged with `err:err = e`.
+#[cfg(feature = "kv")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorChain {
+    /// Only the `Display` output of the error itself is written
+    TopLevel,
+    /// The error is written to its own key, and each error in its `source()` chain is written to
+    /// `<key>_cause_<n>`, starting at 0. The keys are valid Loki label names, as attributes become
+    /// stream labels with the `multistream` feature.
+    #[default]
+    Fields,
+    /// The error and its `source()` chain are joined into a single field, separated by `: `
+    Joined,
+}
+
+#[cfg(all(feature = "kv", any(feature = "logfmt", feature = "json", feature = "pattern")))]
+impl ErrorChain {
+    // Returns the fields to write for the structured field `key` if its value is an error
+    pub(crate) fn fields(&self, key: &str, value: &log::kv::Value) -> Option<Vec<(String, String)>> {
+        let err = value.to_borrowed_error()?;
+        let sources = std::iter::successors(err.source(), |e| e.source());
+
+        Some(match self {
+            ErrorChain::TopLevel => vec![(key.to_owned(), err.to_string())],
+            ErrorChain::Fields => std::iter::once((key.to_owned(), err.to_string()))
+                .chain(
+                    sources
+                        .enumerate()
+                        .map(|(i, e)| (format!("{key}_cause_{i}"), e.to_string())),
+                )
+                .collect(),
+            ErrorChain::Joined => {
+                let mut joined = err.to_string();
+                for source in sources {
+                    joined.push_str(": ");
+                    joined.push_str(&source.to_string());
+                }
+                vec![(key.to_owned(), joined)]
+            },
+        })
+    }
+}
+
+// Combine the file and line of a record into a single `file:line` value
+#[cfg(any(feature = "logfmt", feature = "json"))]
+pub(crate) fn caller(rec: &dyn FormatLog) -> Option<String> {
+    let file = rec.file()?;
+    Some(match rec.line() {
+        Some(line) => format!("{file}:{line}"),
+        None => file.into_owned(),
+    })
+}
+
+// Format a point in time as an RFC 3339 timestamp in UTC with millisecond precision, e.g.
+// 2022-07-01T12:30:00.000Z
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time
+        .duration_since(UNIX_EPOCH)
+        .expect("The current moment is after the Unix Epoch.");
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Convert days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+// Returns the name of the current thread, or its ID if it has no name
+pub(crate) fn thread_name() -> String {
+    let current = thread::current();
+    match current.name() {
+        Some(name) => name.to_owned(),
+        None => thread_id(current.id()),
+    }
+}
+
+// Returns the numeric part of a thread's ID. ThreadId::as_u64 is unstable, so this relies on
+// the Debug output, which looks like `ThreadId(5)`.
+pub(crate) fn thread_id(id: ThreadId) -> String {
+    format!("{id:?}").chars().filter(char::is_ascii_digit).collect()
+}
+
+// Shortens value to at most max bytes, including a marker that says how many bytes were cut. The
+// cut is moved back to the nearest char boundary. If max is shorter than the marker, only the
+// marker is kept.
+pub(crate) fn truncate(value: &mut String, max: usize) {
+    if value.len() <= max {
+        return;
+    }
+
+    let mut cut = max;
+    loop {
+        while !value.is_char_boundary(cut) {
+            cut -= 1;
+        }
+
+        // Cutting more can shorten the count in the marker, so retry until it fits
+        let marker = format!("…[truncated {} bytes]", value.len() - cut);
+        if cut + marker.len() <= max || cut == 0 {
+            value.truncate(cut);
+            value.push_str(&marker);
+            return;
+        }
+        cut = max.saturating_sub(marker.len()).min(cut - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn ordered_attributes_default() {
+        struct Custom;
+
+        impl LokiFormatter for Custom {
+            fn attributes(&self, _rec: &dyn FormatLog) -> HashMap<String, String> {
+                HashMap::from([("host".to_string(), "a".to_string())])
+            }
+        }
+
+        let record = log::Record::builder().args(format_args!("hi")).build();
+        let attributes = Custom.ordered_attributes(&record);
+        assert_eq!(attributes.get("host"), Some("a"));
+        assert_eq!(attributes.len(), 1);
+    }
+
+    #[test]
+    fn rfc3339_format() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_millis(951_827_696_789)),
+            "2000-02-29T12:34:56.789Z"
+        );
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_secs(4_102_444_799)),
+            "2099-12-31T23:59:59.000Z"
+        );
+    }
+
+    #[cfg(all(feature = "kv", any(feature = "logfmt", feature = "json", feature = "pattern")))]
+    #[test]
+    fn error_chain_fields() {
+        #[derive(Debug)]
+        struct Wrapped(&'static str, Option<Box<Wrapped>>);
+
+        impl fmt::Display for Wrapped {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(self.0)
+            }
+        }
+
+        impl std::error::Error for Wrapped {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                self.1.as_deref().map(|e| e as _)
+            }
+        }
+
+        let err = Wrapped("request failed", Some(Box::new(Wrapped("connection reset", None))));
+        let value = log::kv::Value::from_dyn_error(&err);
+
+        assert_eq!(ErrorChain::Fields.fields("err", &value).unwrap(), [
+            ("err".to_owned(), "request failed".to_owned()),
+            ("err_cause_0".to_owned(), "connection reset".to_owned()),
+        ]);
+        assert_eq!(ErrorChain::Joined.fields("err", &value).unwrap(), [(
+            "err".to_owned(),
+            "request failed: connection reset".to_owned()
+        )]);
+        assert_eq!(ErrorChain::TopLevel.fields("err", &value).unwrap().len(), 1);
+        assert!(ErrorChain::Fields.fields("n", &log::kv::Value::from(3)).is_none());
+    }
+
+    #[test]
+    fn truncate_utf8() {
+        let mut short = String::from("short");
+        truncate(&mut short, 5);
+        assert_eq!(short, "short");
+
+        let mut long = "a".repeat(100);
+        truncate(&mut long, 40);
+        assert_eq!(long, format!("{}…[truncated 83 bytes]", "a".repeat(17)));
+        assert_eq!(long.len(), 40);
+
+        // The cut never lands inside a multi-byte character
+        let mut wide = "é".repeat(50);
+        truncate(&mut wide, 40);
+        assert_eq!(wide, format!("{}…[truncated 84 bytes]", "é".repeat(8)));
+
+        let mut tiny = "a".repeat(100);
+        truncate(&mut tiny, 3);
+        assert_eq!(tiny, "…[truncated 100 bytes]");
+    }
 }
@@ -0,0 +1,75 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use std::cell::Cell;
+
+// Crates used to ship logs to Loki. Records from these are never sent to Loki, as a failing push
+// could otherwise produce more logs that trigger more pushes.
+const TRANSPORT_TARGETS: &[&str] = &[
+    "ureq",
+    "ureq_proto",
+    "rustls",
+    "rustls_platform_verifier",
+    "rustls_native_certs",
+    "webpki",
+];
+
+thread_local! {
+    // Set on the worker thread and while a record is being formatted
+    static INTERNAL: Cell<bool> = const { Cell::new(false) };
+}
+
+/// While alive, marks the current thread as belonging to the logger. Records logged on the thread
+/// in the meantime are considered internal.
+pub struct InternalGuard(bool);
+
+impl InternalGuard {
+    pub fn enter() -> Self {
+        InternalGuard(INTERNAL.replace(true))
+    }
+}
+
+impl Drop for InternalGuard {
+    fn drop(&mut self) {
+        INTERNAL.set(self.0);
+    }
+}
+
+/// Returns true while the current thread belongs to the logger.
+pub fn in_logger() -> bool {
+    INTERNAL.get()
+}
+
+/// Returns true if a record with the given target was emitted by the logger itself or by one of
+/// the crates it uses to talk to Loki.
+pub fn is_internal(target: &str) -> bool {
+    in_logger()
+        || TRANSPORT_TARGETS.iter().any(|t| {
+            target
+                .strip_prefix(t)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_targets() {
+        assert!(is_internal("ureq"));
+        assert!(is_internal("rustls::client::hs"));
+        assert!(!is_internal("ureq_helper"));
+        assert!(!is_internal("my_crate"));
+
+        let guard = InternalGuard::enter();
+        assert!(is_internal("my_crate"));
+        drop(guard);
+        assert!(!is_internal("my_crate"));
+    }
+}
@@ -0,0 +1,259 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use std::borrow::Cow;
+use std::time::SystemTime;
+use std::{fmt, process, thread};
+
+#[cfg(feature = "kv")]
+use log::kv::{Error as LogError, Key, Value, VisitSource};
+use serde_json::{Map, Value as Json};
+
+#[cfg(feature = "kv")]
+use crate::fmt::{ErrorChain, kv_to_json};
+use crate::fmt::{caller, rfc3339, thread_id, thread_name};
+use crate::{AutoFields, FieldNames, FormatLog, LevelNames, LokiFormatter};
+
+/// `JsonFormatter` provides a `LokiFormatter` that renders each log as a single line JSON object,
+/// which can be parsed by Loki's `| json` LogQL stage. The message is always included, the
+/// remaining fields are selected with `AutoFields`. Fields from the structured logging API keep
+/// their JSON type where possible.
+#[derive(Default, Debug)]
+pub struct JsonFormatter {
+    include_fields: AutoFields,
+    field_names: FieldNames,
+    level_names: LevelNames,
+    #[cfg(feature = "kv")]
+    error_chain: ErrorChain,
+}
+
+impl JsonFormatter {
+    /// Create a new `JsonFormatter` that includes the fields given in include_fields.
+    pub fn new(include_fields: AutoFields) -> Self {
+        JsonFormatter {
+            include_fields,
+            field_names: FieldNames::default(),
+            level_names: LevelNames::default(),
+            #[cfg(feature = "kv")]
+            error_chain: ErrorChain::default(),
+        }
+    }
+
+    /// Change the keys used for the fields selected by `AutoFields`.
+    pub fn field_names(mut self, field_names: FieldNames) -> Self {
+        self.field_names = field_names;
+        self
+    }
+
+    /// Change how levels are spelled, e.g. to write `warning` instead of `warn`.
+    pub fn level_names(mut self, level_names: LevelNames) -> Self {
+        self.level_names = level_names;
+        self
+    }
+
+    /// Set how structured values that are errors are written. The default is
+    /// `ErrorChain::Fields`, which writes each error in the `source()` chain to its own field.
+    #[cfg(feature = "kv")]
+    pub fn error_chain(mut self, error_chain: ErrorChain) -> Self {
+        self.error_chain = error_chain;
+        self
+    }
+}
+
+impl LokiFormatter for JsonFormatter {
+    fn log_line<'a>(&self, rec: &'a dyn FormatLog) -> Result<Cow<'a, str>, fmt::Error> {
+        let names = &self.field_names;
+        let mut object = Map::new();
+
+        if self.include_fields.contains(AutoFields::TIMESTAMP) {
+            object.insert(names.timestamp.clone(), rfc3339(SystemTime::now()).into());
+        }
+
+        if self.include_fields.contains(AutoFields::LEVEL) {
+            object.insert(names.level.clone(), self.level_names.get(&rec.level()).into());
+        }
+
+        object.insert(names.message.clone(), rec.message().into());
+
+        if self.include_fields.contains(AutoFields::TARGET) {
+            object.insert(names.target.clone(), rec.target().into());
+        }
+
+        if self.include_fields.contains(AutoFields::MODULE_PATH)
+            && let Some(module) = rec.module()
+        {
+            object.insert(names.module.clone(), module.into());
+        }
+
+        if self.include_fields.contains(AutoFields::FILE)
+            && let Some(file) = rec.file()
+        {
+            object.insert(names.file.clone(), file.into());
+        }
+
+        if self.include_fields.contains(AutoFields::LINE)
+            && let Some(line) = rec.line()
+        {
+            let line = line.parse::<u64>().map_or_else(|_| line.into(), Json::from);
+            object.insert(names.line.clone(), line);
+        }
+
+        if self.include_fields.contains(AutoFields::CALLER)
+            && let Some(caller) = caller(rec)
+        {
+            object.insert(names.caller.clone(), caller.into());
+        }
+
+        if self.include_fields.contains(AutoFields::THREAD) {
+            object.insert(names.thread.clone(), thread_name().into());
+        }
+
+        if self.include_fields.contains(AutoFields::THREAD_ID) {
+            let id = thread_id(thread::current().id());
+            let id = id.parse::<u64>().map_or_else(|_| id.into(), Json::from);
+            object.insert(names.thread_id.clone(), id);
+        }
+
+        if self.include_fields.contains(AutoFields::PID) {
+            object.insert(names.pid.clone(), process::id().into());
+        }
+
+        #[cfg(feature = "kv")]
+        if self.include_fields.contains(AutoFields::EXTRA) {
+            rec.key_values()
+                .visit(&mut JsonVisitor {
+                    object: &mut object,
+                    error_chain: self.error_chain,
+                })
+                .expect("This visitor should not return an error");
+        }
+
+        serde_json::to_string(&object).map(Cow::Owned).map_err(|_| fmt::Error)
+    }
+}
+
+// Adds structured fields to the object. Duplicate keys are dropped.
+#[cfg(feature = "kv")]
+struct JsonVisitor<'a> {
+    object: &'a mut Map<String, Json>,
+    error_chain: ErrorChain,
+}
+
+#[cfg(feature = "kv")]
+impl<'kvs> VisitSource<'kvs> for JsonVisitor<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), LogError> {
+        match self.error_chain.fields(key.as_str(), &value) {
+            Some(fields) => {
+                for (k, v) in fields {
+                    self.object.entry(k).or_insert(v.into());
+                }
+            },
+            None => {
+                self.object.entry(key.to_string()).or_insert_with(|| kv_to_json(&value));
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_write_record() {
+        let record = log::Record::builder()
+            .args(format_args!("log \"message\""))
+            .level(log::Level::Info)
+            .target("target")
+            .module_path(Some("module"))
+            .file(Some("file"))
+            .line(Some(1))
+            .build();
+
+        let formatter = JsonFormatter::default();
+        assert_eq!(
+            formatter.log_line(&record).unwrap(),
+            r#"{"level":"info","message":"log \"message\"","module":"module"}"#
+        );
+        assert!(formatter.ordered_attributes(&record).is_empty());
+
+        let record_fields =
+            AutoFields::all() - AutoFields::TIMESTAMP - AutoFields::THREAD - AutoFields::THREAD_ID - AutoFields::PID;
+        let formatter = JsonFormatter::new(record_fields).field_names(FieldNames {
+            level: "lvl".to_owned(),
+            message: "msg".to_owned(),
+            ..Default::default()
+        });
+        assert_eq!(
+            formatter.log_line(&record).unwrap(),
+            r#"{"lvl":"info","msg":"log \"message\"","target":"target","module":"module","file":"file","line":1,"caller":"file:1"}"#
+        );
+    }
+
+    #[test]
+    fn json_process_fields() {
+        let record = log::Record::builder().args(format_args!("m")).build();
+        let formatter = JsonFormatter::new(AutoFields::TIMESTAMP | AutoFields::THREAD_ID | AutoFields::PID);
+
+        let object: Map<String, Json> = serde_json::from_str(&formatter.log_line(&record).unwrap()).unwrap();
+        assert_eq!(object.keys().collect::<Vec<_>>(), [
+            "timestamp",
+            "message",
+            "thread_id",
+            "pid"
+        ]);
+        assert!(object["timestamp"].as_str().unwrap().ends_with('Z'));
+        assert!(object["thread_id"].is_u64());
+        assert_eq!(object["pid"], std::process::id());
+    }
+
+    #[cfg(feature = "kv")]
+    #[test]
+    fn json_typed_kv() {
+        let kvs: &[(&str, log::kv::Value)] = &[
+            ("count", 3u8.into()),
+            ("ratio", 0.5f64.into()),
+            ("ok", true.into()),
+            ("name", "x".into()),
+            ("level", "ignored".into()),
+        ];
+        let record = log::Record::builder()
+            .args(format_args!("m"))
+            .level(log::Level::Warn)
+            .key_values(&kvs)
+            .build();
+
+        assert_eq!(
+            JsonFormatter::default().log_line(&record).unwrap(),
+            r#"{"level":"warn","message":"m","count":3,"ratio":0.5,"ok":true,"name":"x"}"#
+        );
+    }
+
+    #[cfg(feature = "kv")]
+    #[test]
+    fn json_non_finite_kv() {
+        let kvs: &[(&str, log::kv::Value)] = &[
+            ("nan", f64::NAN.into()),
+            ("inf", f64::INFINITY.into()),
+            ("neg", f64::NEG_INFINITY.into()),
+        ];
+        let record = log::Record::builder()
+            .args(format_args!("m"))
+            .level(log::Level::Info)
+            .key_values(&kvs)
+            .build();
+
+        assert_eq!(
+            JsonFormatter::default().log_line(&record).unwrap(),
+            r#"{"level":"info","message":"m","nan":"NaN","inf":"inf","neg":"-inf"}"#
+        );
+        assert_eq!(crate::kv_to_string(&f64::NAN.into()), "NaN");
+        assert_eq!(crate::kv_to_string(&f64::NEG_INFINITY.into()), "-inf");
+    }
+}
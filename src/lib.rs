@@ -7,26 +7,83 @@ file, You can obtain one at http://mozilla.org/MPL/2.0/.
 */
 
 use std::collections::HashMap;
-use std::sync::{Arc, Condvar, Mutex};
+#[cfg(feature = "kv")]
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Condvar, Mutex, PoisonError, Weak};
 use std::thread;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use http::Uri;
 use kanal::{Sender, unbounded};
 use log::{LevelFilter, Log, Metadata, Record, SetLoggerError, set_boxed_logger, set_max_level};
+use serde::Deserialize;
 #[cfg(feature = "tls")]
 use ureq::tls::TlsConfig;
 
 // background task for sending logs to loki
 mod task;
 use task::{LokiTask, LokiTaskMsg};
+// Keeps the logger's own logs out of the pipeline
+mod guard;
+use guard::InternalGuard;
+// Log panics before the process exits
+mod panic;
+// Flush logs on termination signals
+#[cfg(all(feature = "signals", unix))]
+mod signals;
+// Configure the builder from the environment
+mod config;
+pub use config::{ConfigError, FormatterConfig, LokiConfig, TlsFiles, TraceContext};
+// Write logs to the console alongside Loki
+mod console;
+pub use console::{ConsoleLogger, TeeLogger};
+// Request scoped fields attached to every log
+#[cfg(feature = "kv")]
+pub mod context;
+// Per-target level filtering
+mod filter;
+pub use filter::{Directives, LevelHandle, ParseDirectivesError};
 // Write logs in LogFmt style by default
 mod fmt;
-pub use fmt::{FormatLog, LokiFormatter};
+pub use fmt::{Attributes, AutoFields, FieldNames, FieldOrder, FormatLog, LevelNames, LokiFormatter};
+#[cfg(feature = "kv")]
+pub use fmt::{ErrorChain, kv_to_json, kv_to_string};
 #[cfg(feature = "logfmt")]
-mod logfmt;
+pub mod logfmt;
 #[cfg(feature = "logfmt")]
 pub use logfmt::{LogfmtAutoFields, LogfmtFormatter};
+// Write logs as JSON objects
+#[cfg(feature = "json")]
+mod json;
+#[cfg(feature = "json")]
+pub use json::JsonFormatter;
+// Write logs using a template string
+#[cfg(feature = "pattern")]
+mod pattern;
+#[cfg(feature = "pattern")]
+pub use pattern::{PatternError, PatternFormatter};
+// Changes applied to records before they are formatted
+mod record;
+use record::Prepared;
+// Strip secrets from logs before they are sent
+#[cfg(feature = "redact")]
+mod redact;
+#[cfg(feature = "redact")]
+pub use redact::{Mask, RedactionCounters, Redactor};
+// Send events from the tracing ecosystem
+#[cfg(feature = "tracing")]
+mod tracing_layer;
+#[cfg(feature = "tracing")]
+pub use tracing_layer::{LokiLayer, TracingRecord};
+// Send records from slog
+#[cfg(feature = "slog")]
+mod slog_drain;
+#[cfg(feature = "slog")]
+pub use slog_drain::{LokiDrain, SlogRecord};
+// Correlate logs with OpenTelemetry traces
+#[cfg(feature = "opentelemetry")]
+mod otel;
 
 /// `LokiBuilder` is used to construct the `Loki` object.
 #[must_use = "Has no affect unless .build() is called."]
@@ -38,8 +95,19 @@ pub struct LokiBuilder {
     tls_config: Option<Arc<TlsConfig>>,
     max_log_lines: usize,
     max_log_lifetime: Duration,
+    max_line_length: Option<usize>,
+    #[cfg(feature = "kv")]
+    max_field_length: Option<usize>,
+    multiline: MultilineStrategy,
     failure_policy: FailurePolicy,
-    level_filter: LevelFilter,
+    #[cfg(feature = "compress")]
+    compress: bool,
+    internal_log_policy: InternalLogPolicy,
+    directives: Directives,
+    #[cfg(feature = "redact")]
+    redactor: Option<Redactor>,
+    #[cfg(feature = "opentelemetry")]
+    trace_context: Option<(String, String)>,
     formatter: Option<Box<dyn LokiFormatter>>,
 }
 
@@ -56,8 +124,19 @@ impl LokiBuilder {
             tls_config: None, // if unset, uses default
             max_log_lines: 4096,
             max_log_lifetime: Duration::from_secs(300),
+            max_line_length: None,
+            #[cfg(feature = "kv")]
+            max_field_length: None,
+            multiline: MultilineStrategy::Keep,
             failure_policy: FailurePolicy::Retry(6),
-            level_filter: LevelFilter::Trace,
+            #[cfg(feature = "compress")]
+            compress: true,
+            internal_log_policy: InternalLogPolicy::Drop,
+            directives: Directives::default(),
+            #[cfg(feature = "redact")]
+            redactor: None,
+            #[cfg(feature = "opentelemetry")]
+            trace_context: None,
             #[cfg(feature = "logfmt")]
             formatter: Some(Box::new(LogfmtFormatter::default())),
             #[cfg(not(feature = "logfmt"))]
@@ -92,15 +171,101 @@ impl LokiBuilder {
         self
     }
 
+    /// Limits the length of each log line sent to Loki to the given number of bytes, including
+    /// the attributes appended to it when the `multistream` feature is disabled. Use this to stay
+    /// below Loki's `max_line_size`. There is no limit by default.
+    ///
+    /// Lines that are too long are shortened by cutting the end of the message before it is
+    /// formatted, so the output of the formatter stays valid. The cut is made at a character
+    /// boundary and marked with `…[truncated N bytes]`. If that is not enough, for example
+    /// because the other fields are too long, the line itself is cut as a last resort.
+    pub fn max_line_length(mut self, bytes: usize) -> LokiBuilder {
+        self.max_line_length = Some(bytes);
+        self
+    }
+
+    #[cfg(feature = "kv")]
+    /// Limits the length of each structured field value to the given number of bytes. Values are
+    /// cut before they are formatted, in the same way as messages by `.max_line_length()`.
+    /// Values that aren't strings become strings when cut. There is no limit by default.
+    pub fn max_field_length(mut self, bytes: usize) -> LokiBuilder {
+        self.max_field_length = Some(bytes);
+        self
+    }
+
+    /// Specifies how log lines that contain newlines are handled. The default is to send them
+    /// as they are.
+    pub fn multiline(mut self, strategy: MultilineStrategy) -> LokiBuilder {
+        self.multiline = strategy;
+        self
+    }
+
     /// Specifies how failures should be handled. The default is to retry up to 6 times.
     pub fn failure_policy(mut self, fp: FailurePolicy) -> LokiBuilder {
         self.failure_policy = fp;
         self
     }
 
-    /// Sets the verbosity of this logger
+    #[cfg(feature = "compress")]
+    /// Specifies whether pushes are compressed with gzip. The default is to compress them.
+    pub fn compress(mut self, compress: bool) -> LokiBuilder {
+        self.compress = compress;
+        self
+    }
+
+    /// Specifies what happens to records emitted by the logger's own worker thread and by the
+    /// crates it uses to reach Loki (ureq, rustls, ...). These are never sent to Loki. The default
+    /// is to drop them.
+    pub fn internal_log_policy(mut self, ilp: InternalLogPolicy) -> LokiBuilder {
+        self.internal_log_policy = ilp;
+        self
+    }
+
+    /// Sets the verbosity of this logger. Targets matched by a directive keep their own level.
     pub fn level(mut self, lf: LevelFilter) -> LokiBuilder {
-        self.level_filter = lf;
+        self.directives = self.directives.default_level(lf);
+        self
+    }
+
+    /// Sets per-target verbosity using env_logger style directives. Replaces any level set
+    /// previously with `.level()`.
+    pub fn directives(mut self, directives: Directives) -> LokiBuilder {
+        self.directives = directives;
+        self
+    }
+
+    /// Reads env_logger style directives from the environment variable `var`, e.g. `RUST_LOG`.
+    /// If the variable is unset, the current directives are kept. If it cannot be parsed, a
+    /// warning is printed and the current directives are kept.
+    pub fn directives_from_env(mut self, var: &str) -> LokiBuilder {
+        match Directives::from_env(var) {
+            Ok(Some(directives)) => self.directives = directives,
+            Ok(None) => {},
+            Err(err) => eprintln!("(Loki) Ignoring {var}: {err}"),
+        }
+        self
+    }
+
+    #[cfg(feature = "redact")]
+    /// Redact secrets from the message and structured fields of each record before it is
+    /// formatted and sent to Loki.
+    pub fn redactor(mut self, redactor: Redactor) -> LokiBuilder {
+        self.redactor = Some(redactor);
+        self
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    /// Attach the trace and span ID of the current OpenTelemetry span to each log as structured
+    /// fields with the given names, e.g. `trace_id` and `span_id`. Fields the record already has
+    /// are kept. Logs outside of a span are sent as they are. The application must use the same
+    /// version of the opentelemetry crate as this crate for the current span to be found.
+    ///
+    /// The IDs are unique to each span, so they should be part of the log line rather than
+    /// labels. Use a formatter that writes fields into the line, such as `JsonFormatter` or
+    /// `LogfmtFormatter::render_line(true)`. The default `LogfmtFormatter` returns fields as
+    /// attributes, which become stream labels with the `multistream` feature.
+    pub fn trace_context(mut self, trace_id: &str, span_id: &str) -> LokiBuilder {
+        self.trace_context = Some((String::from(trace_id), String::from(span_id)));
         self
     }
 
@@ -115,7 +280,8 @@ impl LokiBuilder {
 }
 
 /// `FailurePolicy` specifies how failures should be handled.
-#[derive(PartialEq, Debug, Clone, Eq)]
+#[derive(PartialEq, Debug, Clone, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum FailurePolicy {
     /// Log batches that fail to send are dropped
     Drop,
@@ -126,60 +292,224 @@ pub enum FailurePolicy {
     Retry(usize),
 }
 
+/// `InternalLogPolicy` specifies what happens to records that originate from the logger itself.
+/// Sending these to Loki could cause a feedback loop, where a failing push logs errors that
+/// trigger more pushes.
+#[derive(PartialEq, Debug, Clone, Copy, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InternalLogPolicy {
+    /// Internal records are discarded
+    Drop,
+    /// Internal records are written to stderr
+    Stderr,
+}
+
+/// `MultilineStrategy` specifies how log lines that span several lines, such as stack traces, are
+/// sent to Loki. It applies to the log line produced by the formatter, except for `Split` with the
+/// `kv` feature, which splits the message before it is formatted.
+#[derive(PartialEq, Debug, Clone, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MultilineStrategy {
+    /// The line is sent as a single entry, newlines included
+    Keep,
+    /// Newlines and carriage returns are escaped as `\n` and `\r`
+    Escape,
+    /// Each line of the message is formatted and sent as its own entry. Entries are timestamped a
+    /// nanosecond apart to keep them in order.
+    ///
+    /// With the `kv` feature, every entry gets a structured field with the given name, whose
+    /// value is shared by the entries so they can be correlated. The formatter writes it like any
+    /// other field. If the formatter returns it as an attribute, like the default
+    /// `LogfmtFormatter`, it is appended to the line as a `name=id` pair instead, so it doesn't
+    /// create a new stream for every message. Without the `kv` feature, the formatted line is
+    /// split and the entries carry no such field.
+    Split(String),
+    /// Continuation lines are indented by the given number of spaces. Blank lines are left empty.
+    Indent(usize),
+}
+
+impl MultilineStrategy {
+    fn apply(&self, log_line: String) -> Vec<String> {
+        if !log_line.contains(['\n', '\r']) {
+            return vec![log_line];
+        }
+
+        match self {
+            MultilineStrategy::Keep => vec![log_line],
+            MultilineStrategy::Escape => vec![log_line.replace('\n', "\\n").replace('\r', "\\r")],
+            MultilineStrategy::Split(_) => {
+                let lines: Vec<_> = split_lines(&log_line)
+                    .filter(|line| !line.is_empty())
+                    .map(String::from)
+                    .collect();
+                if lines.is_empty() { vec![log_line] } else { lines }
+            },
+            MultilineStrategy::Indent(width) => {
+                let indent = " ".repeat(*width);
+                let lines: Vec<_> = split_lines(log_line.trim_end_matches(['\n', '\r']))
+                    .enumerate()
+                    .map(|(i, line)| match line.trim() {
+                        "" => String::new(),
+                        _ if i == 0 => line.to_owned(),
+                        _ => format!("{indent}{line}"),
+                    })
+                    .collect();
+                vec![lines.join("\n")]
+            },
+        }
+    }
+}
+
+// Splits a line on "\r\n", "\n" and a lone "\r"
+fn split_lines(log_line: &str) -> impl Iterator<Item = &str> {
+    let mut rest = Some(log_line);
+    std::iter::from_fn(move || {
+        let line = rest?;
+        match line.find(['\n', '\r']) {
+            Some(end) => {
+                let next = if line[end..].starts_with("\r\n") {
+                    end + 2
+                } else {
+                    end + 1
+                };
+                rest = Some(&line[next..]);
+                Some(&line[..end])
+            },
+            None => {
+                rest = None;
+                Some(line)
+            },
+        }
+    })
+}
+
+// The logger's end of the channel to the worker thread. The worker thread flushes and stops once
+// all strong ends are dropped, so hooks that outlive the logger, like the panic hook, hold a weak
+// end instead.
+#[derive(Clone)]
+enum Tx {
+    Strong(Arc<Sender<LokiTaskMsg>>),
+    Weak(Weak<Sender<LokiTaskMsg>>),
+}
+
+impl Tx {
+    fn get(&self) -> Option<Arc<Sender<LokiTaskMsg>>> {
+        match self {
+            Tx::Strong(tx) => Some(Arc::clone(tx)),
+            Tx::Weak(tx) => tx.upgrade(),
+        }
+    }
+}
+
 /// Logger implementation that writes its logs to Loki. Create one using the `LokiBuilder`.
+/// Cloning a `Loki` is cheap; clones share the same worker thread and settings.
+#[derive(Clone)]
 pub struct Loki {
-    tx: Sender<LokiTaskMsg>,
-    level_filter: LevelFilter,
+    tx: Tx,
+    levels: LevelHandle,
+    internal_log_policy: InternalLogPolicy,
     flush_notif: Arc<(Mutex<bool>, Condvar)>,
-    fmt: Box<dyn LokiFormatter>,
+    #[cfg(feature = "redact")]
+    redactor: Option<Redactor>,
+    #[cfg(feature = "opentelemetry")]
+    trace_context: Option<(String, String)>,
+    max_line_length: Option<usize>,
+    #[cfg(feature = "kv")]
+    max_field_length: Option<usize>,
+    multiline: MultilineStrategy,
+    // Source of correlation IDs for split entries
+    #[cfg(feature = "kv")]
+    multiline_seq: Arc<AtomicU64>,
+    fmt: Arc<dyn LokiFormatter>,
 }
 
 impl Loki {
     fn start(builder: LokiBuilder) -> Self {
-        let filter = builder.level_filter;
         let (tx, rx) = unbounded::<LokiTaskMsg>();
         let flush_notif = Arc::new((Mutex::new(false), Condvar::new()));
-        let flush_notif2 = Arc::clone(&flush_notif);
-        let fmt = builder.formatter;
 
         #[cfg(feature = "tls")]
         let loki = LokiTask::new(
             rx,
-            flush_notif2,
-            builder.endpoint,
-            builder.headers,
-            builder.labels,
+            Arc::clone(&flush_notif),
+            builder.endpoint.clone(),
+            builder.headers.clone(),
+            builder.labels.clone(),
             builder.max_log_lines,
             builder.max_log_lifetime,
-            builder.failure_policy,
-            builder.tls_config,
+            builder.failure_policy.clone(),
+            builder.tls_config.clone(),
         );
         #[cfg(not(feature = "tls"))]
         let loki = LokiTask::new(
             rx,
-            flush_notif2,
-            builder.endpoint,
-            builder.headers,
-            builder.labels,
+            Arc::clone(&flush_notif),
+            builder.endpoint.clone(),
+            builder.headers.clone(),
+            builder.labels.clone(),
             builder.max_log_lines,
             builder.max_log_lifetime,
-            builder.failure_policy,
+            builder.failure_policy.clone(),
         );
 
+        #[cfg(feature = "compress")]
+        let loki = loki.compress(builder.compress);
+
         thread::spawn(move || {
+            let _guard = InternalGuard::enter();
             loki.run();
         });
 
+        Loki::with_channel(builder, tx, flush_notif)
+    }
+
+    // Creates the logger's side of the channel to the worker thread
+    fn with_channel(builder: LokiBuilder, tx: Sender<LokiTaskMsg>, flush_notif: Arc<(Mutex<bool>, Condvar)>) -> Self {
+        let directives = builder.directives;
+        let internal_log_policy = builder.internal_log_policy;
+        let fmt = builder.formatter;
+
         Self {
-            tx,
-            level_filter: filter,
+            tx: Tx::Strong(Arc::new(tx)),
+            levels: LevelHandle::new(directives),
+            internal_log_policy,
             flush_notif,
-            fmt: fmt.expect("When the logfmt feature is disabled, you are required to provide a formatter."),
+            #[cfg(feature = "redact")]
+            redactor: builder.redactor,
+            #[cfg(feature = "opentelemetry")]
+            trace_context: builder.trace_context,
+            max_line_length: builder.max_line_length,
+            #[cfg(feature = "kv")]
+            max_field_length: builder.max_field_length,
+            multiline: builder.multiline,
+            #[cfg(feature = "kv")]
+            multiline_seq: Arc::new(AtomicU64::new(0)),
+            fmt: Arc::from(fmt.expect("When the logfmt feature is disabled, you are required to provide a formatter.")),
         }
     }
 
+    // Returns a clone that doesn't keep the worker thread running. Once the logger and all its
+    // other clones are dropped, logs sent through it are discarded.
+    fn downgrade(&self) -> Loki {
+        let tx = match &self.tx {
+            Tx::Strong(tx) => Tx::Weak(Arc::downgrade(tx)),
+            Tx::Weak(tx) => Tx::Weak(Weak::clone(tx)),
+        };
+        Loki { tx, ..self.clone() }
+    }
+
+    /// Returns the most verbose level enabled by this logger's directives.
     pub fn level_filter(&self) -> LevelFilter {
-        self.level_filter
+        self.levels.directives.read().unwrap().max_level()
+    }
+
+    pub fn directives(&self) -> Directives {
+        self.levels.directives()
+    }
+
+    /// Returns a handle that can be used to change the verbosity of this logger at runtime.
+    pub fn level_handle(&self) -> LevelHandle {
+        self.levels.clone()
     }
 
     pub fn fmt(&self) -> &dyn LokiFormatter {
@@ -189,8 +519,13 @@ impl Loki {
     /// Installs the logger as the default logger for the entire program.
     /// Calling this (or any similar function from other libraries) more than once is a bug.
     pub fn apply(self) -> Result<(), SetLoggerError> {
-        set_max_level(self.level_filter);
-        set_boxed_logger(Box::from(self))
+        let levels = self.levels.clone();
+        let directives = levels.directives.read().unwrap();
+
+        set_boxed_logger(Box::from(self))?;
+        set_max_level(levels.max_level(&directives));
+        levels.installed.store(true, Ordering::Release);
+        Ok(())
     }
 
     pub fn send_log(&self, record: &dyn FormatLog) {
@@ -199,21 +534,183 @@ impl Loki {
             .expect("The current moment is after the Unix Epoch.")
             .as_nanos();
 
-        let log_line = self.fmt.log_line(record).expect("LokiFormatters shouldn't fail here.");
-        let attributes = self.fmt.attributes(record);
+        // Anything the formatter logs is internal
+        let _guard = InternalGuard::enter();
+        let mut record = Prepared::new(record);
+        #[cfg(feature = "kv")]
+        context::add_to(&mut record);
+        #[cfg(feature = "opentelemetry")]
+        if let (Some((trace_key, span_key)), Some((trace_id, span_id))) =
+            (&self.trace_context, otel::current_trace_ids())
+        {
+            record.add_field(trace_key, trace_id);
+            record.add_field(span_key, span_id);
+        }
+        #[cfg(feature = "redact")]
+        if let Some(redactor) = &self.redactor {
+            record.redact(redactor);
+        }
+        #[cfg(feature = "kv")]
+        if let Some(max) = self.max_field_length {
+            record.truncate_fields(max);
+        }
+
+        // The logger is gone if only a weak end of the channel is left
+        let Some(tx) = self.tx.get() else {
+            return;
+        };
+        let entries = self.format(&mut record, now);
+        for (i, (log_line, attributes)) in entries.into_iter().enumerate() {
+            tx.send(LokiTaskMsg::Log(now + i as u128, log_line, attributes))
+                .expect("The other thread should be running.");
+        }
+    }
+
+    // Formats the record into the entries sent to Loki, shortening its message until each line
+    // fits in max_line_length
+    fn format(&self, record: &mut Prepared, now: u128) -> Vec<(String, Attributes)> {
+        // The time is only used for the IDs of split entries
+        #[cfg(not(feature = "kv"))]
+        let _ = now;
+        #[cfg(feature = "kv")]
+        let entry_id = match &self.multiline {
+            MultilineStrategy::Split(_) => {
+                let seq = self.multiline_seq.fetch_add(1, Ordering::Relaxed);
+                format!("{now:x}-{seq:x}")
+            },
+            _ => String::new(),
+        };
+        #[cfg(feature = "kv")]
+        let format_once = |record: &Prepared| self.format_once(record, &entry_id);
+        #[cfg(not(feature = "kv"))]
+        let format_once = |record: &Prepared| self.format_once(record);
+
+        let mut entries = format_once(record);
+        let Some(max) = self.max_line_length else {
+            return entries;
+        };
+        let longest = |entries: &[(String, Attributes)]| {
+            entries
+                .iter()
+                .map(|(log_line, attributes)| task::entry_len(log_line, attributes))
+                .max()
+                .unwrap_or(0)
+        };
+
+        // Formatters may escape the message, so it can take more than one attempt
+        for _ in 0..3 {
+            let excess = longest(&entries).saturating_sub(max);
+            let len = record.message().len();
+            if excess == 0 || len == 0 {
+                break;
+            }
+
+            record.truncate_message(len.saturating_sub(excess));
+            entries = format_once(record);
+        }
 
-        self.tx
-            .send(LokiTaskMsg::Log(now, log_line.into(), attributes))
-            .expect("The other thread should be running.");
+        if longest(&entries) > max {
+            #[allow(unused_variables)]
+            for (log_line, attributes) in &mut entries {
+                // Without the multistream feature, the attributes are part of the line that is cut
+                #[cfg(not(feature = "multistream"))]
+                {
+                    task::append_attributes(log_line, attributes);
+                    *attributes = Attributes::new();
+                }
+                fmt::truncate(log_line, max);
+            }
+        }
+
+        entries
+    }
+
+    fn format_once(&self, record: &Prepared, #[cfg(feature = "kv")] entry_id: &str) -> Vec<(String, Attributes)> {
+        #[cfg(feature = "kv")]
+        if let MultilineStrategy::Split(field) = &self.multiline {
+            let message = record.message();
+            let lines: Vec<_> = split_lines(&message)
+                .filter(|line| !line.is_empty())
+                .map(String::from)
+                .collect();
+            if lines.len() > 1 {
+                return lines
+                    .into_iter()
+                    .map(|line| {
+                        let mut entry = record.clone();
+                        entry.set_message(line);
+                        entry.add_field(field, entry_id.to_owned());
+                        let (mut log_line, mut attributes) = self.format_record(&entry);
+                        // Keep the ID out of the labels when the formatter returns fields as attributes
+                        if let Some(value) = attributes.remove(field) {
+                            log_line.push_str(&format!(" {field}={value}"));
+                        }
+                        (log_line, attributes)
+                    })
+                    .collect();
+            }
+        }
+
+        let (log_line, attributes) = self.format_record(record);
+        let log_lines = self.multiline.apply(log_line);
+        let count = log_lines.len();
+        let mut attributes = Some(attributes);
+        log_lines
+            .into_iter()
+            .enumerate()
+            .map(|(i, log_line)| {
+                let attributes = if i + 1 == count {
+                    attributes.take().unwrap_or_default()
+                } else {
+                    attributes.clone().unwrap_or_default()
+                };
+                (log_line, attributes)
+            })
+            .collect()
+    }
+
+    fn format_record(&self, record: &Prepared) -> (String, Attributes) {
+        let log_line: String = self
+            .fmt
+            .log_line(record)
+            .expect("LokiFormatters shouldn't fail here.")
+            .into();
+        #[allow(unused_mut)]
+        let mut attributes = self.fmt.ordered_attributes(record);
+        #[cfg(feature = "redact")]
+        if let Some(redactor) = &self.redactor {
+            record.redact_attributes(redactor, &mut attributes);
+        }
+        (log_line, attributes)
+    }
+
+    // Sends a record that passed the level filter, unless it was emitted by the logger itself
+    pub(crate) fn dispatch(&self, record: &dyn FormatLog) {
+        let target = record.target();
+
+        if guard::is_internal(&target) {
+            if self.internal_log_policy == InternalLogPolicy::Stderr {
+                eprintln!(
+                    "(Loki) {} {}: {}",
+                    record.level().to_uppercase(),
+                    target,
+                    record.message()
+                );
+            }
+            return;
+        }
+
+        self.send_log(record);
     }
 
     pub fn send_and_white_flush(&self) {
         let (mtx, cvar) = &*self.flush_notif;
+        let Some(tx) = self.tx.get() else {
+            return;
+        };
         let mut flushed = mtx.lock().unwrap();
 
-        self.tx
-            .send(LokiTaskMsg::Flush)
-            .expect("The other thread should be running");
+        tx.send(LokiTaskMsg::Flush).expect("The other thread should be running");
 
         *flushed = false;
 
@@ -221,11 +718,30 @@ impl Loki {
             flushed = cvar.wait(flushed).unwrap();
         }
     }
+
+    /// Like `send_and_white_flush()`, but gives up after `timeout`. Returns true if the logs were
+    /// flushed in time.
+    pub fn flush_timeout(&self, timeout: Duration) -> bool {
+        let (mtx, cvar) = &*self.flush_notif;
+        // This may run while panicking, so don't give up on a poisoned lock
+        let mut flushed = mtx.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if self.tx.get().is_none_or(|tx| tx.send(LokiTaskMsg::Flush).is_err()) {
+            return false;
+        }
+
+        *flushed = false;
+
+        let (flushed, _) = cvar
+            .wait_timeout_while(flushed, timeout, |flushed| !*flushed)
+            .unwrap_or_else(PoisonError::into_inner);
+        *flushed
+    }
 }
 
 impl Log for Loki {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= self.level_filter
+        self.levels.directives.read().unwrap().enabled(metadata)
     }
 
     fn log(&self, record: &Record) {
@@ -233,10 +749,235 @@ impl Log for Loki {
             return;
         }
 
-        self.send_log(record as &dyn FormatLog);
+        self.dispatch(record);
     }
 
     fn flush(&self) {
         self.send_and_white_flush();
     }
 }
+
+// Lets tests see what a logger would send to Loki, without a worker thread
+#[cfg(test)]
+pub(crate) struct Captured(kanal::Receiver<LokiTaskMsg>);
+
+#[cfg(test)]
+impl Captured {
+    // Returns the logs sent since the last call
+    pub(crate) fn logs(&self) -> Vec<(String, Attributes)> {
+        let mut logs = Vec::new();
+        while let Ok(Some(msg)) = self.0.try_recv() {
+            if let LokiTaskMsg::Log(_, log_line, attributes) = msg {
+                logs.push((log_line, attributes));
+            }
+        }
+        logs
+    }
+}
+
+// Returns the logs sent since the last call, parsed as JSON objects
+#[cfg(all(test, feature = "json", any(feature = "tracing", feature = "slog")))]
+impl Captured {
+    pub(crate) fn json(&self) -> Vec<serde_json::Map<String, serde_json::Value>> {
+        self.logs()
+            .into_iter()
+            .map(|(log_line, _)| serde_json::from_str(&log_line).expect("The log line should be JSON"))
+            .collect()
+    }
+}
+
+// A builder for a logger that is only used through Loki::capture
+#[cfg(test)]
+pub(crate) fn test_builder() -> LokiBuilder {
+    let labels = HashMap::from([("app".to_owned(), "test".to_owned())]);
+    LokiBuilder::new("http://localhost:3100".parse().unwrap(), labels)
+}
+
+#[cfg(test)]
+impl Loki {
+    pub(crate) fn capture(builder: LokiBuilder) -> (Loki, Captured) {
+        let (tx, rx) = unbounded::<LokiTaskMsg>();
+        let flush_notif = Arc::new((Mutex::new(false), Condvar::new()));
+        (Loki::with_channel(builder, tx, flush_notif), Captured(rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiline_strategies() {
+        let trace = || String::from("panicked\n  at main.rs:1\r\n\n  at lib.rs:2\n");
+
+        assert_eq!(MultilineStrategy::Keep.apply(trace()), [trace()]);
+        assert_eq!(MultilineStrategy::Escape.apply(trace()), [
+            r"panicked\n  at main.rs:1\r\n\n  at lib.rs:2\n"
+        ]);
+        assert_eq!(MultilineStrategy::Split("trace_id".into()).apply(trace()), [
+            "panicked",
+            "  at main.rs:1",
+            "  at lib.rs:2"
+        ]);
+        assert_eq!(MultilineStrategy::Indent(4).apply(trace()), [
+            "panicked\n      at main.rs:1\n\n      at lib.rs:2"
+        ]);
+        assert_eq!(MultilineStrategy::Indent(4).apply("single".into()), ["single"]);
+
+        // A lone carriage return ends a line too
+        let old_mac = || String::from("first\rsecond\r\rthird");
+        assert_eq!(MultilineStrategy::Split("trace_id".into()).apply(old_mac()), [
+            "first", "second", "third"
+        ]);
+        assert_eq!(MultilineStrategy::Indent(2).apply(old_mac()), [
+            "first\n  second\n\n  third"
+        ]);
+    }
+
+    #[cfg(all(feature = "kv", feature = "logfmt"))]
+    #[test]
+    fn split_entries_share_an_id() {
+        let (loki, captured) = Loki::capture(test_builder().multiline(MultilineStrategy::Split("entry".into())));
+        loki.send_log(&Record::builder().args(format_args!("one\ntwo")).build());
+
+        let logs = captured.logs();
+        assert_eq!(logs.len(), 2);
+        let id = logs[0].0.rsplit_once(" entry=").unwrap().1;
+        assert!(logs[1].0.ends_with(&format!(" entry={id}")));
+        assert!(logs.iter().all(|(_, attributes)| !attributes.contains_key("entry")));
+    }
+
+    #[cfg(all(feature = "kv", feature = "json"))]
+    #[test]
+    fn split_json_entries() {
+        let builder = test_builder()
+            .formatter(Box::new(JsonFormatter::default()))
+            .multiline(MultilineStrategy::Split("entry".into()));
+        let (loki, captured) = Loki::capture(builder);
+        loki.send_log(
+            &Record::builder()
+                .args(format_args!("panicked\n  at main.rs:1\r\n"))
+                .build(),
+        );
+
+        let logs = captured.logs();
+        assert_eq!(logs.len(), 2);
+        let entries: Vec<serde_json::Value> = logs
+            .iter()
+            .map(|(log_line, _)| serde_json::from_str(log_line).unwrap())
+            .collect();
+        assert_eq!(entries[0]["message"], "panicked");
+        assert_eq!(entries[1]["message"], "  at main.rs:1");
+        assert!(entries[0]["entry"].is_string());
+        assert_eq!(entries[0]["entry"], entries[1]["entry"]);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn line_limit_keeps_json_valid() {
+        let (loki, captured) = Loki::capture(
+            test_builder()
+                .formatter(Box::new(JsonFormatter::default()))
+                .max_line_length(120),
+        );
+        let message = "\"quoted\" ".repeat(40);
+        loki.send_log(&Record::builder().args(format_args!("{message}")).build());
+
+        let logs = captured.logs();
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].0.len() <= 120);
+        let value: serde_json::Value = serde_json::from_str(&logs[0].0).unwrap();
+        assert!(value["message"].as_str().unwrap().ends_with(" bytes]"));
+    }
+
+    #[cfg(all(feature = "logfmt", feature = "kv"))]
+    #[test]
+    fn field_and_line_limits_keep_logfmt_valid() {
+        let (loki, captured) = Loki::capture(
+            test_builder()
+                .formatter(Box::new(LogfmtFormatter::default()))
+                .max_field_length(30)
+                .max_line_length(120),
+        );
+        let kvs: &[(&str, log::kv::Value)] = &[("note", "say \"hello\" to everyone in the room".into())];
+        let message = "a long message ".repeat(10);
+        loki.send_log(
+            &Record::builder()
+                .args(format_args!("{message}"))
+                .key_values(&kvs)
+                .build(),
+        );
+
+        let logs = captured.logs();
+        assert_eq!(logs.len(), 1);
+        let (log_line, attributes) = &logs[0];
+        assert!(task::entry_len(log_line, attributes) <= 120);
+        assert!(log_line.starts_with("a long message") && log_line.ends_with(" bytes]"));
+        // Appending the attributes to the line keeps it valid when multistream is disabled
+        let pairs = attributes.iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>();
+        let pairs = logfmt::decode(&pairs.join(" ")).unwrap();
+        assert_eq!(
+            pairs[1],
+            ("note".to_owned(), "say \"he…[truncated 28 bytes]".to_owned())
+        );
+    }
+
+    #[cfg(all(feature = "opentelemetry", feature = "json"))]
+    #[test]
+    fn trace_ids_in_line() {
+        use opentelemetry::Context;
+        use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState};
+
+        let builder = test_builder()
+            .formatter(Box::new(JsonFormatter::default()))
+            .trace_context("trace_id", "span_id");
+        let (loki, captured) = Loki::capture(builder);
+        let span_context = SpanContext::new(
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        let _attached = Context::current().with_remote_span_context(span_context).attach();
+        loki.send_log(&Record::builder().args(format_args!("traced")).build());
+
+        let logs = captured.logs();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(
+            logs[0].0,
+            r#"{"level":"info","message":"traced","trace_id":"4bf92f3577b34da6a3ce929d0e0e4736","span_id":"00f067aa0ba902b7"}"#
+        );
+        assert!(logs[0].1.is_empty());
+    }
+
+    #[cfg(all(feature = "logfmt", feature = "kv"))]
+    #[test]
+    fn context_fields_in_line() {
+        let builder = test_builder().formatter(Box::new(LogfmtFormatter::default().render_line(true)));
+        let (loki, captured) = Loki::capture(builder);
+        context::scope([("user", "ann \"a\" lee")], || {
+            loki.send_log(&Record::builder().args(format_args!("hello")).build());
+        });
+
+        let logs = captured.logs();
+        assert_eq!(logs.len(), 1);
+        assert!(!logs[0].1.contains_key("user"));
+        let pairs = logfmt::decode(&logs[0].0).unwrap();
+        assert!(pairs.contains(&("user".to_owned(), "ann \"a\" lee".to_owned())));
+    }
+
+    #[cfg(feature = "logfmt")]
+    #[test]
+    fn downgraded_logger_does_not_keep_worker() {
+        let (loki, captured) = Loki::capture(test_builder());
+        let weak = loki.downgrade();
+        weak.send_log(&Record::builder().args(format_args!("sent")).build());
+
+        drop(loki);
+        assert!(captured.0.is_disconnected());
+        weak.send_log(&Record::builder().args(format_args!("dropped")).build());
+        assert!(!weak.flush_timeout(Duration::from_millis(10)));
+        assert_eq!(captured.logs().len(), 1);
+    }
+}
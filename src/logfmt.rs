@@ -6,16 +6,33 @@ License, v. 2.0. If a copy of the MPL was not distributed with this
 file, You can obtain one at http://mozilla.org/MPL/2.0/.
 */
 
-use std::collections::HashMap;
-
-use bitflags::bitflags;
-#[cfg(feature = "kv_unstable")]
-use log::kv::{Key, Value, Visitor, value::Error as LogError};
+//! Encoding and decoding of logfmt lines.
 
-use crate::{FormatLog, LokiFormatter};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::iter::Peekable;
+use std::str::CharIndices;
+use std::time::SystemTime;
+use std::{fmt, process, thread};
+
+#[cfg(feature = "kv")]
+use log::kv::{Error as LogError, Key, Value, VisitSource};
+
+#[cfg(feature = "kv")]
+use crate::fmt::{ErrorChain, kv_to_string};
+use crate::fmt::{caller, rfc3339, thread_id, thread_name};
+use crate::{Attributes, AutoFields, FieldNames, FieldOrder, FormatLog, LevelNames, LokiFormatter};
+
+// Returns true for characters that may not appear in logfmt keys
+fn is_invalid_key_char(c: char) -> bool {
+    c <= ' ' || c == '=' || c == '"' || c.is_control()
+}
 
-// Contains all characters that may not appear in logfmt keys
-const INVALID_KEY_CHARS: &[char] = &[' ', '=', '"'];
+// Returns true for characters that may only appear in quoted logfmt values
+fn needs_quotes(c: char) -> bool {
+    c <= ' ' || c == '=' || c == '"' || c == '\\' || c.is_control()
+}
 
 /// `LogfmtFormatter` provides a `LokiFormatter` that marshals logs using the logfmt format, which is a
 /// plain text log format that is easy for both humans and machines to read and write. Loki provides
@@ -24,125 +41,189 @@ const INVALID_KEY_CHARS: &[char] = &[' ', '=', '"'];
 /// To learn more about logfmt, see: <https://www.brandur.org/logfmt>
 #[derive(Default, Debug)]
 pub struct LogfmtFormatter {
-    include_fields: LogfmtAutoFields,
+    include_fields: AutoFields,
     escape_newlines: bool,
+    render_line: bool,
+    field_order: FieldOrder,
+    field_names: FieldNames,
+    level_names: LevelNames,
+    #[cfg(feature = "kv")]
+    error_chain: ErrorChain,
 }
 
+/// The set of fields automatically inserted by the `LogfmtFormatter`. This is an alias of
+/// `AutoFields`, which is shared by all built-in formatters.
+pub type LogfmtAutoFields = AutoFields;
+
 impl LogfmtFormatter {
     /// Create a new `LogfmtFormatter`. The created formatter will automatically insert fields
     /// depending on the value of include_fields. See `LogfmtAutoFields` for more details.
     /// \r, \n, and \t can be optionally escaped depending on the value of escape_newlines, but
     /// Loki does not require this.
-    pub fn new(include_fields: LogfmtAutoFields, escape_newlines: bool) -> Self {
+    pub fn new(include_fields: AutoFields, escape_newlines: bool) -> Self {
         LogfmtFormatter {
             include_fields,
             escape_newlines,
+            render_line: false,
+            field_order: FieldOrder::Insertion,
+            field_names: FieldNames::default(),
+            level_names: LevelNames::default(),
+            #[cfg(feature = "kv")]
+            error_chain: ErrorChain::default(),
         }
     }
 
+    /// Set how structured values that are errors are written. The default is
+    /// `ErrorChain::Fields`, which writes each error in the `source()` chain to its own field.
+    #[cfg(feature = "kv")]
+    pub fn error_chain(mut self, error_chain: ErrorChain) -> Self {
+        self.error_chain = error_chain;
+        self
+    }
+
+    /// Change the keys used for the fields selected by `LogfmtAutoFields`, e.g. to write `lvl`
+    /// and `msg` instead of `level` and `message`.
+    pub fn field_names(mut self, field_names: FieldNames) -> Self {
+        self.field_names = field_names;
+        self
+    }
+
+    /// Change how levels are spelled, e.g. to write `warning` instead of `warn`.
+    pub fn level_names(mut self, level_names: LevelNames) -> Self {
+        self.level_names = level_names;
+        self
+    }
+
+    /// Set the order in which fields are written, both as attributes and when rendering the whole
+    /// line. The default is `FieldOrder::Insertion`.
+    pub fn field_order(mut self, field_order: FieldOrder) -> Self {
+        self.field_order = field_order;
+        self
+    }
+
+    /// If enabled, the whole entry is rendered as a logfmt line, e.g.
+    /// `level=info message="hello world" module=app key=val`, and no attributes are produced. This
+    /// keeps fields out of the stream labels when the `multistream` feature is enabled, while
+    /// still allowing them to be extracted with `| logfmt` in LogQL. The message is always
+    /// included in this mode. Fields are written in a stable order: the automatic fields first,
+    /// then any structured fields in the order they were given.
+    pub fn render_line(mut self, render_line: bool) -> Self {
+        self.render_line = render_line;
+        self
+    }
+
     /// Write a key value pair to the underlying string. Duplicate keys are dropped.
-    fn write_pair(&self, attributes: &mut HashMap<String, String>, mut key: String, val: &str) {
+    fn write_pair(&self, fields: &mut Attributes, mut key: String, val: &str) {
         // Normalize the key
-        key.retain(|c| {
-            for invalid_char in INVALID_KEY_CHARS {
-                if c == *invalid_char {
-                    return false;
-                }
-            }
-            true
-        });
+        key.retain(|c| !is_invalid_key_char(c));
         if key.is_empty() {
             key.push('_');
         }
 
         // ensure uniqueness of the key
-        if attributes.contains_key(&key) {
+        if fields.contains_key(&key) {
+            return;
+        }
+
+        // values are written as is, unless they contain something that must be quoted
+        if !val.chars().any(needs_quotes) {
+            fields.insert(key, val.to_owned());
             return;
         }
 
-        // reformat the value if needed
-        let mut formatted_value = String::new();
-        formatted_value.reserve(val.len() + 10);
-        let mut need_quotes = false;
+        let mut formatted_value = String::with_capacity(val.len() + 10);
+        formatted_value.push('"');
         for chr in val.chars() {
             match chr {
                 '\\' | '"' => {
-                    need_quotes = true;
                     formatted_value.push('\\');
                     formatted_value.push(chr);
                 },
-                ' ' | '=' => {
-                    need_quotes = true;
-                    formatted_value.push(chr);
-                },
-
-                '\n' | '\r' | '\t' => {
-                    need_quotes = true;
-
-                    if self.escape_newlines {
-                        formatted_value.push('\\');
-                    }
-
-                    formatted_value.push(chr);
-                },
-                _ => {
-                    if !chr.is_control() {
-                        formatted_value.push(chr);
-                    } else {
-                        need_quotes = true;
-                        formatted_value.push_str(&chr.escape_unicode().to_string());
-                    }
+                '\n' if self.escape_newlines => formatted_value.push_str("\\n"),
+                '\r' if self.escape_newlines => formatted_value.push_str("\\r"),
+                '\t' if self.escape_newlines => formatted_value.push_str("\\t"),
+                '\n' | '\r' | '\t' => formatted_value.push(chr),
+                _ if chr.is_control() => {
+                    // All control characters are in the BMP, so four hex digits always suffice
+                    formatted_value.push_str(&format!("\\u{:04x}", chr as u32));
                 },
+                _ => formatted_value.push(chr),
             }
         }
-        if need_quotes {
-            formatted_value.push('"');
-        }
+        formatted_value.push('"');
 
-        attributes.insert(key, formatted_value);
+        fields.insert(key, formatted_value);
     }
-}
 
-impl LokiFormatter for LogfmtFormatter {
-    fn attributes(&self, rec: &dyn FormatLog) -> HashMap<String, String> {
-        let mut attributes = HashMap::new();
-        attributes.reserve(10);
+    /// Collect the fields of the record in the order they should be written.
+    fn fields(&self, rec: &dyn FormatLog) -> Attributes {
+        let mut attributes = Attributes::with_capacity(10);
+
+        let names = &self.field_names;
+
+        // The timestamp and thread differ between almost all records, so they are left out when the
+        // attributes become stream labels, which would have unbounded cardinality.
+        let labels = cfg!(feature = "multistream") && !self.render_line;
+
+        if !labels && self.include_fields.contains(AutoFields::TIMESTAMP) {
+            self.write_pair(&mut attributes, names.timestamp.clone(), &rfc3339(SystemTime::now()));
+        }
 
-        if self.include_fields.contains(LogfmtAutoFields::LEVEL) {
-            self.write_pair(&mut attributes, "level".to_owned(), &rec.level());
+        if self.include_fields.contains(AutoFields::LEVEL) {
+            let level = rec.level();
+            self.write_pair(&mut attributes, names.level.clone(), self.level_names.get(&level));
         }
 
         let message = rec.message();
-        if self.include_fields.contains(LogfmtAutoFields::MESSAGE) && !message.is_empty() {
-            self.write_pair(&mut attributes, "message".to_owned(), &message);
+        if self.render_line || (self.include_fields.contains(AutoFields::MESSAGE) && !message.is_empty()) {
+            self.write_pair(&mut attributes, names.message.clone(), &message);
         }
 
         let target = rec.target();
-        if self.include_fields.contains(LogfmtAutoFields::TARGET) && !target.is_empty() {
-            self.write_pair(&mut attributes, "target".to_owned(), &target);
+        if self.include_fields.contains(AutoFields::TARGET) && !target.is_empty() {
+            self.write_pair(&mut attributes, names.target.clone(), &target);
         }
 
-        if self.include_fields.contains(LogfmtAutoFields::MODULE_PATH)
+        if self.include_fields.contains(AutoFields::MODULE_PATH)
             && let Some(module) = rec.module()
         {
-            self.write_pair(&mut attributes, "module".to_owned(), &module);
+            self.write_pair(&mut attributes, names.module.clone(), &module);
         }
 
-        if self.include_fields.contains(LogfmtAutoFields::FILE)
+        if self.include_fields.contains(AutoFields::FILE)
             && let Some(file) = rec.file()
         {
-            self.write_pair(&mut attributes, "file".to_owned(), &file);
+            self.write_pair(&mut attributes, names.file.clone(), &file);
         }
 
         let line = rec.line();
-        if self.include_fields.contains(LogfmtAutoFields::LINE)
+        if self.include_fields.contains(AutoFields::LINE)
             && let Some(line) = line
         {
-            self.write_pair(&mut attributes, "line".to_owned(), &line);
+            self.write_pair(&mut attributes, names.line.clone(), &line);
         }
 
-        #[cfg(feature = "kv_unstable")]
-        if self.include_fields.contains(LogfmtAutoFields::EXTRA) {
+        if self.include_fields.contains(AutoFields::CALLER)
+            && let Some(caller) = caller(rec)
+        {
+            self.write_pair(&mut attributes, names.caller.clone(), &caller);
+        }
+
+        if !labels && self.include_fields.contains(AutoFields::THREAD) {
+            self.write_pair(&mut attributes, names.thread.clone(), &thread_name());
+        }
+
+        if !labels && self.include_fields.contains(AutoFields::THREAD_ID) {
+            let id = thread_id(thread::current().id());
+            self.write_pair(&mut attributes, names.thread_id.clone(), &id);
+        }
+
+        if self.include_fields.contains(AutoFields::PID) {
+            self.write_pair(&mut attributes, names.pid.clone(), &process::id().to_string());
+        }
+
+        #[cfg(feature = "kv")]
+        if self.include_fields.contains(AutoFields::EXTRA) {
             rec.key_values()
                 .visit(&mut LogfmtVisitor {
                     fmt: self,
@@ -151,67 +232,297 @@ impl LokiFormatter for LogfmtFormatter {
                 .expect("This visitor should not return an error");
         }
 
+        attributes.sort(&self.field_order);
         attributes
     }
 }
 
-#[cfg(feature = "kv_unstable")]
+impl LokiFormatter for LogfmtFormatter {
+    fn log_line<'a>(&self, rec: &'a dyn FormatLog) -> Result<Cow<'a, str>, fmt::Error> {
+        if !self.render_line {
+            return Ok(rec.message());
+        }
+
+        let line = self
+            .fields(rec)
+            .into_iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<String>>()
+            .join(" ");
+        Ok(Cow::Owned(line))
+    }
+
+    fn attributes(&self, rec: &dyn FormatLog) -> HashMap<String, String> {
+        self.ordered_attributes(rec).into_iter().collect()
+    }
+
+    fn ordered_attributes(&self, rec: &dyn FormatLog) -> Attributes {
+        if self.render_line {
+            return Attributes::new();
+        }
+
+        self.fields(rec)
+    }
+}
+
+#[cfg(feature = "kv")]
 struct LogfmtVisitor<'a> {
     fmt: &'a LogfmtFormatter,
-    attributes: &'a mut HashMap<String, String>,
+    attributes: &'a mut Attributes,
 }
 
-#[cfg(feature = "kv_unstable")]
-impl<'a, 'kvs> Visitor<'kvs> for LogfmtVisitor<'a> {
+#[cfg(feature = "kv")]
+impl<'kvs> VisitSource<'kvs> for LogfmtVisitor<'_> {
     fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), LogError> {
-        self.fmt
-            .write_pair(self.attributes, key.to_string(), &value.to_string());
+        match self.fmt.error_chain.fields(key.as_str(), &value) {
+            Some(fields) => {
+                for (k, v) in fields {
+                    self.fmt.write_pair(self.attributes, k, &v);
+                }
+            },
+            None => {
+                self.fmt
+                    .write_pair(self.attributes, key.to_string(), &kv_to_string(&value));
+            },
+        }
         Ok(())
     }
 }
 
-bitflags! {
-    /// `LogfmtAutoFields` is used to determine what fields of a log::Record should be rendered into
-    /// the final logfmt string by the `LogfmtFormatter`. The default set is LEVEL | MESSAGE | MODULE_PATH
-    /// | EXTRA
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-    pub struct LogfmtAutoFields: u32 {
-        /// Include a `level` field indicating the level the message was logged at.
-        const LEVEL = 1;
-        /// Include the `message` field containing the message passed to the log directive
-        const MESSAGE = 1 << 1;
-        /// Include a `target` field, corresponding to the target of the log directive
-        const TARGET = 1 << 2;
-        /// Include the `module` field set on the log record
-        const MODULE_PATH = 1 << 3;
-        /// Include the `file` field set on the log record
-        const FILE = 1 << 4;
-        /// Include the `line` field associated with the log directive.
-        const LINE = 1 << 5;
-        /// Include any extra fields specified via the structured logging API, if enabled.
-        #[cfg(feature = "kv_unstable")]
-        const EXTRA = 1 << 6;
+/// Decode a logfmt line into its key value pairs, in the order they appear. Keys without a value
+/// (`key` or `key=`) decode to an empty value. Quoted values may contain the escapes `\"`, `\\`,
+/// `\/`, `\b`, `\f`, `\n`, `\r`, `\t` and `\uXXXX`, as well as raw characters such as newlines.
+pub fn decode(line: &str) -> Result<Vec<(String, String)>, DecodeError> {
+    let mut pairs = Vec::new();
+    let mut chars = line.char_indices().peekable();
+
+    loop {
+        while chars.next_if(|(_, c)| *c <= ' ').is_some() {}
+        let Some(&(start, _)) = chars.peek() else {
+            return Ok(pairs);
+        };
+
+        let mut key = String::new();
+        while let Some((pos, c)) = chars.next_if(|(_, c)| *c > ' ' && *c != '=') {
+            if c == '"' {
+                return Err(DecodeError::new(pos, "unexpected '\"' in key"));
+            }
+            key.push(c);
+        }
+        if key.is_empty() {
+            return Err(DecodeError::new(start, "missing key"));
+        }
+
+        let mut value = String::new();
+        if chars.next_if(|(_, c)| *c == '=').is_some() {
+            if chars.next_if(|(_, c)| *c == '"').is_some() {
+                decode_quoted(&mut chars, &mut value, start)?;
+                if let Some(&(pos, c)) = chars.peek()
+                    && c > ' '
+                {
+                    return Err(DecodeError::new(pos, "unexpected character after quoted value"));
+                }
+            } else {
+                while let Some((pos, c)) = chars.next_if(|(_, c)| *c > ' ') {
+                    if c == '"' || c == '=' {
+                        return Err(DecodeError::new(pos, "unexpected character in unquoted value"));
+                    }
+                    value.push(c);
+                }
+            }
+        }
+
+        pairs.push((key, value));
     }
 }
 
-impl Default for LogfmtAutoFields {
-    fn default() -> Self {
-        #[cfg(feature = "kv_unstable")]
-        {
-            LogfmtAutoFields::LEVEL | LogfmtAutoFields::MODULE_PATH | LogfmtAutoFields::EXTRA
+// Decode the remainder of a quoted value, up to and including the closing quote.
+fn decode_quoted(chars: &mut Peekable<CharIndices>, value: &mut String, start: usize) -> Result<(), DecodeError> {
+    loop {
+        let Some((pos, c)) = chars.next() else {
+            return Err(DecodeError::new(start, "unterminated quoted value"));
+        };
+
+        match c {
+            '"' => return Ok(()),
+            '\\' => {
+                let Some((_, escape)) = chars.next() else {
+                    return Err(DecodeError::new(pos, "unterminated escape"));
+                };
+                match escape {
+                    '"' | '\\' | '/' => value.push(escape),
+                    'b' => value.push('\u{8}'),
+                    'f' => value.push('\u{c}'),
+                    'n' => value.push('\n'),
+                    'r' => value.push('\r'),
+                    't' => value.push('\t'),
+                    'u' => {
+                        let mut code = decode_hex(chars, pos)?;
+                        // Characters outside of the BMP are written as a UTF-16 surrogate pair
+                        if (0xD800..0xDC00).contains(&code) {
+                            if chars.next().map(|(_, c)| c) != Some('\\') || chars.next().map(|(_, c)| c) != Some('u') {
+                                return Err(DecodeError::new(pos, "unpaired surrogate"));
+                            }
+                            let low = decode_hex(chars, pos)?;
+                            if !(0xDC00..0xE000).contains(&low) {
+                                return Err(DecodeError::new(pos, "unpaired surrogate"));
+                            }
+                            code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                        }
+                        value.push(char::from_u32(code).ok_or(DecodeError::new(pos, "invalid unicode escape"))?);
+                    },
+                    _ => return Err(DecodeError::new(pos, "invalid escape")),
+                }
+            },
+            _ => value.push(c),
         }
+    }
+}
 
-        #[cfg(not(feature = "kv_unstable"))]
-        {
-            LogfmtAutoFields::LEVEL | LogfmtAutoFields::MODULE_PATH
-        }
+// Decode the four hex digits of a \u escape.
+fn decode_hex(chars: &mut Peekable<CharIndices>, pos: usize) -> Result<u32, DecodeError> {
+    let mut code = 0;
+    for _ in 0..4 {
+        let digit = chars
+            .next()
+            .and_then(|(_, c)| c.to_digit(16))
+            .ok_or(DecodeError::new(pos, "invalid unicode escape"))?;
+        code = code * 16 + digit;
     }
+    Ok(code)
 }
 
+/// Returned by `decode` when a line is not valid logfmt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pos: usize,
+    reason: &'static str,
+}
+
+impl DecodeError {
+    fn new(pos: usize, reason: &'static str) -> Self {
+        DecodeError { pos, reason }
+    }
+
+    /// The byte offset in the line at which the error was found.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid logfmt at byte {}: {}", self.pos, self.reason)
+    }
+}
+
+impl Error for DecodeError {}
+
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
 
+    fn encode(formatter: &LogfmtFormatter, pairs: &[(String, String)]) -> String {
+        let mut fields = Attributes::new();
+        for (k, v) in pairs {
+            formatter.write_pair(&mut fields, k.clone(), v);
+        }
+        fields
+            .into_iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn logfmt_quoting() {
+        let formatter = LogfmtFormatter::default();
+        let encoded = |v: &str| encode(&formatter, &[("k".to_owned(), v.to_owned())]);
+
+        assert_eq!(encoded("plain"), "k=plain");
+        assert_eq!(encoded(""), "k=");
+        assert_eq!(encoded("hello world"), r#"k="hello world""#);
+        assert_eq!(encoded("a=b"), r#"k="a=b""#);
+        assert_eq!(encoded(r#"say "hi""#), r#"k="say \"hi\"""#);
+        assert_eq!(encoded(r"C:\dir"), r#"k="C:\\dir""#);
+        assert_eq!(encoded("bell\u{7}"), r#"k="bell\u0007""#);
+        assert_eq!(encoded("a\nb"), "k=\"a\nb\"");
+
+        let formatter = LogfmtFormatter::new(AutoFields::default(), true);
+        assert_eq!(
+            encode(&formatter, &[("k".to_owned(), "a\nb".to_owned())]),
+            r#"k="a\nb""#
+        );
+        assert_eq!(
+            encode(&formatter, &[("a key=\"x\"".to_owned(), "v".to_owned())]),
+            "akeyx=v"
+        );
+        assert_eq!(encode(&formatter, &[(" ".to_owned(), "v".to_owned())]), "_=v");
+    }
+
+    #[test]
+    fn logfmt_decode() {
+        let pairs = |p: &[(&str, &str)]| -> Vec<(String, String)> {
+            p.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+
+        assert_eq!(decode("").unwrap(), pairs(&[]));
+        assert_eq!(
+            decode(r#" a=1 b="x y" flag c= d="\"q\" \\ é 😀" "#).unwrap(),
+            pairs(&[
+                ("a", "1"),
+                ("b", "x y"),
+                ("flag", ""),
+                ("c", ""),
+                ("d", "\"q\" \\ é 😀")
+            ])
+        );
+        assert_eq!(decode("a=\"line\nbreak\"").unwrap(), pairs(&[("a", "line\nbreak")]));
+
+        assert_eq!(decode("=1").unwrap_err().position(), 0);
+        assert_eq!(decode("a=1 b=\"open").unwrap_err().position(), 4);
+        assert!(decode(r#"a="\x""#).is_err());
+        assert!(decode(r#"a="\u12""#).is_err());
+        assert!(decode(r#"a="\ud83d""#).is_err());
+        assert!(decode(r#"a="x"y"#).is_err());
+        assert!(decode(r#"a=x"y"#).is_err());
+        assert!(decode(r#"a"b=1"#).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn logfmt_value_round_trip(value in any::<String>(), escape_newlines in any::<bool>()) {
+            let formatter = LogfmtFormatter::new(AutoFields::default(), escape_newlines);
+            let line = encode(&formatter, &[("key".to_owned(), value.clone())]);
+
+            prop_assert_eq!(decode(&line).unwrap(), vec![("key".to_owned(), value)]);
+        }
+
+        #[test]
+        fn logfmt_line_round_trip(pairs in prop::collection::vec((any::<String>(), any::<String>()), 0..8)) {
+            let formatter = LogfmtFormatter::new(AutoFields::default(), true);
+            let line = encode(&formatter, &pairs);
+
+            // Escaped lines never span more than one line
+            prop_assert!(!line.contains('\n'));
+
+            // Keys are normalized and deduplicated, values must survive unchanged
+            let mut expected: Vec<(String, String)> = Vec::new();
+            for (k, v) in pairs {
+                let mut k: String = k.chars().filter(|c| !is_invalid_key_char(*c)).collect();
+                if k.is_empty() {
+                    k.push('_');
+                }
+                if !expected.iter().any(|(e, _)| *e == k) {
+                    expected.push((k, v));
+                }
+            }
+            prop_assert_eq!(decode(&line).unwrap(), expected);
+        }
+    }
+
     #[test]
     fn logfmt_write_record() {
         let record = log::Record::builder()
@@ -225,15 +536,12 @@ mod tests {
 
         let formatter = LogfmtFormatter::default();
         let log_line = formatter.log_line(&record).unwrap();
-        let attributes = formatter.attributes(&record);
+        let attributes = formatter.ordered_attributes(&record);
 
         assert_eq!(log_line, "log message");
         assert_eq!(
             attributes,
-            [("level", "info"), ("module", "module")]
-                .into_iter()
-                .map(|(k, v)| (k.into(), v.into()))
-                .collect()
+            [("level", "info"), ("module", "module")].into_iter().collect()
         );
 
         let formatter = LogfmtFormatter::new(
@@ -241,7 +549,7 @@ mod tests {
             false,
         );
         let log_line = formatter.log_line(&record).unwrap();
-        let attributes = formatter.attributes(&record);
+        let attributes = formatter.ordered_attributes(&record);
 
         assert_eq!(log_line, "log message");
         assert_eq!(
@@ -254,8 +562,129 @@ mod tests {
                 ("line", "1")
             ]
             .into_iter()
-            .map(|(k, v)| (k.into(), v.into()))
             .collect()
         );
     }
+
+    #[test]
+    fn logfmt_field_order() {
+        let record = log::Record::builder()
+            .args(format_args!("message"))
+            .level(log::Level::Info)
+            .target("target")
+            .module_path(Some("module"))
+            .build();
+        let fields = AutoFields::LEVEL | AutoFields::TARGET | AutoFields::MODULE_PATH;
+
+        let formatter = LogfmtFormatter::new(fields, false).field_order(FieldOrder::Sorted);
+        let attributes = formatter.ordered_attributes(&record);
+        assert_eq!(attributes.iter().collect::<Vec<_>>(), [
+            ("level", "info"),
+            ("module", "module"),
+            ("target", "target")
+        ]);
+
+        let formatter = LogfmtFormatter::new(fields, false)
+            .field_order(FieldOrder::Explicit(vec!["module".into(), "message".into()]))
+            .render_line(true);
+        assert_eq!(
+            formatter.log_line(&record).unwrap(),
+            "module=module message=message level=info target=target"
+        );
+    }
+
+    #[test]
+    fn logfmt_field_names() {
+        let record = log::Record::builder()
+            .args(format_args!("message"))
+            .level(log::Level::Warn)
+            .file(Some("src/main.rs"))
+            .line(Some(12))
+            .build();
+
+        let formatter = LogfmtFormatter::new(AutoFields::LEVEL | AutoFields::CALLER, false)
+            .field_names(FieldNames {
+                level: "lvl".into(),
+                message: "msg".into(),
+                ..Default::default()
+            })
+            .level_names(LevelNames {
+                warn: "warning".into(),
+                ..Default::default()
+            })
+            .render_line(true);
+        assert_eq!(
+            formatter.log_line(&record).unwrap(),
+            "lvl=warning msg=message caller=src/main.rs:12"
+        );
+    }
+
+    #[cfg(feature = "kv")]
+    #[test]
+    fn logfmt_typed_kv() {
+        #[derive(serde::Serialize)]
+        struct User {
+            id: u32,
+            name: &'static str,
+        }
+
+        let user = User { id: 7, name: "ann" };
+        let kvs: &[(&str, Value)] = &[
+            ("count", 3u8.into()),
+            ("ok", true.into()),
+            ("text", "a b".into()),
+            ("user", Value::from_serde(&user)),
+        ];
+        let record = log::Record::builder().args(format_args!("m")).key_values(&kvs).build();
+
+        let formatter = LogfmtFormatter::new(AutoFields::EXTRA, true).render_line(true);
+        assert_eq!(
+            formatter.log_line(&record).unwrap(),
+            r#"message=m count=3 ok=true text="a b" user="{\"id\":7,\"name\":\"ann\"}""#
+        );
+    }
+
+    #[test]
+    fn logfmt_render_line() {
+        let record = log::Record::builder()
+            .args(format_args!("message"))
+            .level(log::Level::Info)
+            .target("target")
+            .module_path(Some("module"))
+            .file(Some("file"))
+            .line(Some(1))
+            .build();
+
+        let record_fields =
+            AutoFields::all() - AutoFields::TIMESTAMP - AutoFields::THREAD - AutoFields::THREAD_ID - AutoFields::PID;
+        let formatter = LogfmtFormatter::new(record_fields, false).render_line(true);
+        assert_eq!(
+            formatter.log_line(&record).unwrap(),
+            "level=info message=message target=target module=module file=file line=1 caller=file:1"
+        );
+        assert!(formatter.ordered_attributes(&record).is_empty());
+
+        let formatter = LogfmtFormatter::new(
+            AutoFields::THREAD | AutoFields::PID | AutoFields::TIMESTAMP | AutoFields::THREAD_ID,
+            false,
+        );
+        let attributes = thread::Builder::new()
+            .name("worker".into())
+            .spawn(move || {
+                let record = log::Record::builder().args(format_args!("m")).build();
+                formatter.ordered_attributes(&record)
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(attributes.get("pid"), Some(process::id().to_string().as_str()));
+        if cfg!(feature = "multistream") {
+            assert_eq!(attributes.len(), 1);
+        } else {
+            assert_eq!(attributes.get("thread"), Some("worker"));
+            assert!(attributes.contains_key("thread_id"));
+            assert!(attributes.contains_key("timestamp"));
+            assert_eq!(attributes.len(), 4);
+        }
+    }
 }
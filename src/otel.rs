@@ -0,0 +1,51 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use opentelemetry::Context;
+use opentelemetry::trace::TraceContextExt;
+
+/// Returns the trace and span ID of the current OpenTelemetry span as lowercase hex strings, or
+/// `None` if there is no valid span.
+pub fn current_trace_ids() -> Option<(String, String)> {
+    let cx = Context::current();
+    let span = cx.span();
+    let span_context = span.span_context();
+
+    span_context
+        .is_valid()
+        .then(|| (span_context.trace_id().to_string(), span_context.span_id().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::{SpanContext, SpanId, TraceFlags, TraceId, TraceState};
+
+    use super::*;
+
+    #[test]
+    fn trace_ids_of_current_span() {
+        assert_eq!(current_trace_ids(), None);
+
+        let span_context = SpanContext::new(
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        let _attached = Context::current().with_remote_span_context(span_context).attach();
+
+        assert_eq!(
+            current_trace_ids(),
+            Some((
+                "4bf92f3577b34da6a3ce929d0e0e4736".to_owned(),
+                "00f067aa0ba902b7".to_owned()
+            ))
+        );
+    }
+}
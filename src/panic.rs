@@ -0,0 +1,102 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use std::any::Any;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::fmt::Write;
+use std::panic::{self, PanicHookInfo};
+use std::time::Duration;
+
+use log::{Level, Record};
+
+use crate::fmt::thread_name;
+use crate::{Loki, guard};
+
+impl Loki {
+    /// Installs a panic hook that logs panics at error level, waits up to `flush_timeout` for
+    /// the logs to be flushed and then calls the previously installed hook. The record has the
+    /// target `panic` and contains the panic message, its location and the panicking thread.
+    /// Without the `multistream` feature, the location and thread are also added as `location`
+    /// and `thread` fields; with it they would become stream labels.
+    /// A backtrace is included if enabled through `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`.
+    ///
+    /// The hook is not affected by the logger's directives, and doesn't keep the logger alive:
+    /// panics after the logger and all its clones are dropped are not logged.
+    pub fn install_panic_hook(&self, flush_timeout: Duration) {
+        let loki = self.downgrade();
+        let previous = panic::take_hook();
+
+        panic::set_hook(Box::new(move |info| {
+            loki.log_panic(info, flush_timeout);
+            previous(info);
+        }));
+    }
+
+    fn log_panic(&self, info: &PanicHookInfo<'_>, flush_timeout: Duration) {
+        // A panic on the worker thread can't be sent by the worker thread
+        if guard::in_logger() {
+            return;
+        }
+
+        let thread = thread_name();
+        let location = info.location().map(ToString::to_string).unwrap_or_default();
+        let mut message = format!(
+            "thread '{thread}' panicked at {location}:\n{}",
+            payload_str(info.payload())
+        );
+
+        let backtrace = Backtrace::capture();
+        if backtrace.status() == BacktraceStatus::Captured {
+            write!(message, "\nstack backtrace:\n{backtrace}").expect("Writing to a String can't fail.");
+        }
+
+        // As attributes these would become stream labels
+        #[cfg(all(feature = "kv", not(feature = "multistream")))]
+        let kvs: &[(&str, log::kv::Value)] = &[
+            ("thread", thread.as_str().into()),
+            ("location", location.as_str().into()),
+        ];
+
+        let mut builder = Record::builder();
+        builder
+            .level(Level::Error)
+            .target("panic")
+            .file(info.location().map(|l| l.file()))
+            .line(info.location().map(|l| l.line()));
+        #[cfg(all(feature = "kv", not(feature = "multistream")))]
+        builder.key_values(&kvs);
+
+        self.dispatch(&builder.args(format_args!("{message}")).build());
+        self.flush_timeout(flush_timeout);
+    }
+}
+
+// Panic payloads are almost always a &str or a String
+fn payload_str(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panic_payload() {
+        let payload = |f: fn()| panic::catch_unwind(f).unwrap_err();
+
+        assert_eq!(payload_str(&*payload(|| panic!("static"))), "static");
+        assert_eq!(payload_str(&*payload(|| panic!("formatted {}", 1))), "formatted 1");
+        assert_eq!(payload_str(&*payload(|| panic::panic_any(7))), "Box<dyn Any>");
+    }
+}
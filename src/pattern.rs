@@ -0,0 +1,322 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{process, thread};
+
+#[cfg(feature = "kv")]
+use log::kv::{Error as LogError, Key, Value, VisitSource};
+
+#[cfg(feature = "kv")]
+use crate::fmt::{ErrorChain, kv_to_string};
+use crate::fmt::{rfc3339, thread_id, thread_name};
+use crate::{FormatLog, LokiFormatter};
+
+/// `PatternFormatter` provides a `LokiFormatter` that renders log lines from a template string,
+/// similar to the patterns used by log4rs or fern, e.g. `"{timestamp} {level:>5} [{target}] {message} {kv}"`.
+/// The template is parsed once when the formatter is created.
+///
+/// The following placeholders are supported:
+///  - `{level}`, `{message}`, `{target}`, `{module}`, `{file}` and `{line}` - the corresponding fields of the record.
+///    Missing fields render as an empty string.
+///  - `{thread}` - the name of the thread that logged the record, or its ID if it has no name
+///  - `{thread_id}` - the ID of the thread that logged the record
+///  - `{pid}` - the ID of the current process
+///  - `{timestamp}` - the current time as an RFC 3339 timestamp in UTC
+///  - `{timestamp_ms}` - the current time as milliseconds since the Unix epoch
+///  - `{kv}` - structured fields as space separated `key=value` pairs, if enabled
+///
+/// Placeholders can be padded to a minimum width with `{name:N}`, optionally preceded by `<`, `>`
+/// or `^` to align left (the default), right or center. Literal braces are written as `{{` and `}}`.
+#[derive(Debug, Clone)]
+pub struct PatternFormatter {
+    segments: Vec<Segment>,
+    #[cfg(feature = "kv")]
+    error_chain: ErrorChain,
+}
+
+impl PatternFormatter {
+    /// Create a new `PatternFormatter` from the given template.
+    pub fn new(template: &str) -> Result<Self, PatternError> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.char_indices().peekable();
+
+        while let Some((pos, c)) = chars.next() {
+            match c {
+                '{' if chars.next_if(|(_, c)| *c == '{').is_some() => literal.push('{'),
+                '}' if chars.next_if(|(_, c)| *c == '}').is_some() => literal.push('}'),
+                '}' => return Err(PatternError::new(pos, "unmatched '}'")),
+                '{' => {
+                    let mut spec = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '}')) => break,
+                            Some((_, c)) => spec.push(c),
+                            None => return Err(PatternError::new(pos, "unterminated placeholder")),
+                        }
+                    }
+
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::parse_placeholder(&spec, pos)?);
+                },
+                _ => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(PatternFormatter {
+            segments,
+            #[cfg(feature = "kv")]
+            error_chain: ErrorChain::default(),
+        })
+    }
+
+    /// Set how structured values that are errors are rendered by `{kv}`. The default is
+    /// `ErrorChain::Fields`, which renders each error in the `source()` chain as its own pair.
+    #[cfg(feature = "kv")]
+    pub fn error_chain(mut self, error_chain: ErrorChain) -> Self {
+        self.error_chain = error_chain;
+        self
+    }
+}
+
+impl FromStr for PatternFormatter {
+    type Err = PatternError;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        PatternFormatter::new(template)
+    }
+}
+
+impl LokiFormatter for PatternFormatter {
+    fn log_line<'a>(&self, rec: &'a dyn FormatLog) -> Result<Cow<'a, str>, fmt::Error> {
+        let mut line = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => line.push_str(text),
+                Segment::Placeholder(placeholder, align, width) => {
+                    let value = placeholder.render(rec, self);
+                    match align {
+                        Align::Left => write!(line, "{value:<width$}")?,
+                        Align::Right => write!(line, "{value:>width$}")?,
+                        Align::Center => write!(line, "{value:^width$}")?,
+                    }
+                },
+            }
+        }
+
+        Ok(Cow::Owned(line))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder, Align, usize),
+}
+
+impl Segment {
+    // Parse the contents of a placeholder, e.g. `level:>5`
+    fn parse_placeholder(spec: &str, pos: usize) -> Result<Self, PatternError> {
+        let (name, format) = spec.split_once(':').unwrap_or((spec, ""));
+
+        let placeholder = match name.trim() {
+            "level" => Placeholder::Level,
+            "message" => Placeholder::Message,
+            "target" => Placeholder::Target,
+            "module" => Placeholder::Module,
+            "file" => Placeholder::File,
+            "line" => Placeholder::Line,
+            "thread" => Placeholder::Thread,
+            "thread_id" => Placeholder::ThreadId,
+            "pid" => Placeholder::Pid,
+            "timestamp" => Placeholder::Timestamp,
+            "timestamp_ms" => Placeholder::TimestampMillis,
+            "kv" => Placeholder::KeyValues,
+            _ => return Err(PatternError::new(pos, "unknown placeholder")),
+        };
+
+        let (align, width) = match format.chars().next() {
+            Some('<') => (Align::Left, &format[1..]),
+            Some('>') => (Align::Right, &format[1..]),
+            Some('^') => (Align::Center, &format[1..]),
+            _ => (Align::Left, format),
+        };
+        let width = if width.is_empty() {
+            0
+        } else {
+            width.parse().map_err(|_| PatternError::new(pos, "invalid width"))?
+        };
+
+        Ok(Segment::Placeholder(placeholder, align, width))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Level,
+    Message,
+    Target,
+    Module,
+    File,
+    Line,
+    Thread,
+    ThreadId,
+    Pid,
+    Timestamp,
+    TimestampMillis,
+    KeyValues,
+}
+
+impl Placeholder {
+    #[allow(unused_variables)]
+    fn render<'a>(&self, rec: &'a dyn FormatLog, fmt: &PatternFormatter) -> Cow<'a, str> {
+        match self {
+            Placeholder::Level => rec.level(),
+            Placeholder::Message => rec.message(),
+            Placeholder::Target => rec.target(),
+            Placeholder::Module => rec.module().unwrap_or_default(),
+            Placeholder::File => rec.file().unwrap_or_default(),
+            Placeholder::Line => rec.line().unwrap_or_default(),
+            Placeholder::Thread => Cow::Owned(thread_name()),
+            Placeholder::ThreadId => Cow::Owned(thread_id(thread::current().id())),
+            Placeholder::Pid => Cow::Owned(process::id().to_string()),
+            Placeholder::Timestamp => Cow::Owned(rfc3339(SystemTime::now())),
+            Placeholder::TimestampMillis => Cow::Owned(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("The current moment is after the Unix Epoch.")
+                    .as_millis()
+                    .to_string(),
+            ),
+            #[cfg(feature = "kv")]
+            Placeholder::KeyValues => {
+                let mut visitor = PatternVisitor {
+                    line: String::new(),
+                    error_chain: fmt.error_chain,
+                };
+                rec.key_values()
+                    .visit(&mut visitor)
+                    .expect("This visitor should not return an error");
+                Cow::Owned(visitor.line)
+            },
+            #[cfg(not(feature = "kv"))]
+            Placeholder::KeyValues => Cow::Borrowed(""),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+// Renders structured fields as space separated key=value pairs
+#[cfg(feature = "kv")]
+struct PatternVisitor {
+    line: String,
+    error_chain: ErrorChain,
+}
+
+#[cfg(feature = "kv")]
+impl<'kvs> VisitSource<'kvs> for PatternVisitor {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), LogError> {
+        let fields = self
+            .error_chain
+            .fields(key.as_str(), &value)
+            .unwrap_or_else(|| vec![(key.to_string(), kv_to_string(&value))]);
+
+        for (k, v) in fields {
+            if !self.line.is_empty() {
+                self.line.push(' ');
+            }
+            write!(self.line, "{k}={v}").map_err(LogError::boxed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returned when a `PatternFormatter` template cannot be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatternError {
+    pos: usize,
+    reason: &'static str,
+}
+
+impl PatternError {
+    fn new(pos: usize, reason: &'static str) -> Self {
+        PatternError { pos, reason }
+    }
+
+    /// The byte offset in the template at which the error was found.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid pattern at byte {}: {}", self.pos, self.reason)
+    }
+}
+
+impl Error for PatternError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern_write_record() {
+        let record = log::Record::builder()
+            .args(format_args!("log message"))
+            .level(log::Level::Info)
+            .target("target")
+            .module_path(Some("module"))
+            .line(Some(7))
+            .build();
+
+        let formatter =
+            PatternFormatter::new("{level:>5} [{target}] {{{module}:{line}}} {file}|{message:^13}|").unwrap();
+        assert_eq!(
+            formatter.log_line(&record).unwrap(),
+            " info [target] {module:7} | log message |"
+        );
+        assert!(formatter.ordered_attributes(&record).is_empty());
+
+        let formatter = PatternFormatter::new("{thread}").unwrap();
+        let line = thread::Builder::new()
+            .name("worker".into())
+            .spawn(move || {
+                let record = log::Record::builder().args(format_args!("m")).build();
+                formatter.log_line(&record).unwrap().into_owned()
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(line, "worker");
+
+        assert_eq!(PatternFormatter::new("a {level").unwrap_err().position(), 2);
+        assert_eq!(PatternFormatter::new("a } b").unwrap_err().position(), 2);
+        assert!(PatternFormatter::new("{nope}").is_err());
+        assert!(PatternFormatter::new("{level:>x}").is_err());
+    }
+}
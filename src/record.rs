@@ -0,0 +1,369 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use std::borrow::Cow;
+#[cfg(all(feature = "kv", feature = "redact"))]
+use std::error::Error;
+#[cfg(all(feature = "kv", feature = "redact"))]
+use std::fmt as stdfmt;
+
+#[cfg(feature = "kv")]
+use log::kv::{Error as LogError, Key, Source, Value, VisitSource};
+
+#[cfg(feature = "kv")]
+use crate::fmt::kv_to_string;
+#[cfg(feature = "redact")]
+use crate::{Attributes, Redactor};
+use crate::{FormatLog, fmt};
+
+// A record whose message and structured fields are changed before it is formatted. The changes
+// are made to the raw values, so formatters quote and encode the results like any other value.
+#[derive(Clone)]
+pub(crate) struct Prepared<'a> {
+    rec: &'a dyn FormatLog,
+    message: Option<String>,
+    // Only collected once a field has to be changed
+    #[cfg(feature = "kv")]
+    fields: Option<Fields<'a>>,
+}
+
+impl<'a> Prepared<'a> {
+    pub(crate) fn new(rec: &'a dyn FormatLog) -> Self {
+        Prepared {
+            rec,
+            message: None,
+            #[cfg(feature = "kv")]
+            fields: None,
+        }
+    }
+
+    // Masks secrets in the message and in the structured fields
+    #[cfg(feature = "redact")]
+    pub(crate) fn redact(&mut self, redactor: &Redactor) {
+        if let Cow::Owned(message) = redactor.redact_value(&self.message()) {
+            self.message = Some(message);
+        }
+
+        #[cfg(feature = "kv")]
+        self.fields_mut().0.retain_mut(|(key, field)| {
+            // Formatters may write the whole source() chain of an error, so each error is redacted
+            if let Field::Value(value) = field
+                && let Some(err) = value.to_borrowed_error()
+                && !redactor.denies(key)
+            {
+                if let Some(redacted) = RedactedError::new(redactor, err) {
+                    *field = Field::Error(redacted);
+                }
+                return true;
+            }
+
+            let redacted = match redactor.redact_field(key, &field.text()) {
+                None => return false,
+                Some(Cow::Borrowed(_)) => return true,
+                Some(Cow::Owned(redacted)) => redacted,
+            };
+            *field = Field::Owned(redacted);
+            true
+        });
+    }
+
+    // Adds a structured field, unless the record already has one with the same key
+    #[cfg(feature = "kv")]
+    pub(crate) fn add_field(&mut self, key: &str, value: String) {
+        let fields = self.fields_mut();
+        if !fields.0.iter().any(|(k, _)| k == key) {
+            fields.0.push((Cow::Owned(key.to_owned()), Field::Owned(value)));
+        }
+    }
+
+    // Masks secrets in attributes the formatter added on its own. Attributes made from structured
+    // fields were redacted with the fields.
+    #[cfg(feature = "redact")]
+    pub(crate) fn redact_attributes(&self, redactor: &Redactor, attributes: &mut Attributes) {
+        attributes.retain_mut(|key, value| {
+            #[cfg(feature = "kv")]
+            if self.key_values().get(Key::from_str(key)).is_some() {
+                return true;
+            }
+
+            match redactor.redact_field(key, value) {
+                None => false,
+                Some(Cow::Borrowed(_)) => true,
+                Some(Cow::Owned(redacted)) => {
+                    *value = redacted;
+                    true
+                },
+            }
+        });
+    }
+
+    // Replaces the message
+    #[cfg(feature = "kv")]
+    pub(crate) fn set_message(&mut self, message: String) {
+        self.message = Some(message);
+    }
+
+    // Cuts the message down to at most max bytes
+    pub(crate) fn truncate_message(&mut self, max: usize) {
+        let mut message = self.message().into_owned();
+        fmt::truncate(&mut message, max);
+        self.message = Some(message);
+    }
+
+    // Cuts the values of structured fields down to at most max bytes
+    #[cfg(feature = "kv")]
+    pub(crate) fn truncate_fields(&mut self, max: usize) {
+        for (_, field) in &mut self.fields_mut().0 {
+            let text = field.text();
+            if text.len() > max {
+                let mut text = text.into_owned();
+                fmt::truncate(&mut text, max);
+                *field = Field::Owned(text);
+            }
+        }
+    }
+
+    #[cfg(feature = "kv")]
+    fn fields_mut(&mut self) -> &mut Fields<'a> {
+        let rec = self.rec;
+        self.fields.get_or_insert_with(|| {
+            let mut fields = Fields(Vec::new());
+            rec.key_values()
+                .visit(&mut fields)
+                .expect("This visitor should not return an error");
+            fields
+        })
+    }
+}
+
+impl FormatLog for Prepared<'_> {
+    fn level(&self) -> Cow<'_, str> {
+        self.rec.level()
+    }
+
+    fn message(&self) -> Cow<'_, str> {
+        match &self.message {
+            Some(message) => Cow::Borrowed(message),
+            None => self.rec.message(),
+        }
+    }
+
+    fn target(&self) -> Cow<'_, str> {
+        self.rec.target()
+    }
+
+    fn module(&self) -> Option<Cow<'_, str>> {
+        self.rec.module()
+    }
+
+    fn file(&self) -> Option<Cow<'_, str>> {
+        self.rec.file()
+    }
+
+    fn line(&self) -> Option<Cow<'_, str>> {
+        self.rec.line()
+    }
+
+    #[cfg(feature = "kv")]
+    fn key_values(&self) -> &dyn log::kv::Source {
+        match &self.fields {
+            Some(fields) => fields,
+            None => self.rec.key_values(),
+        }
+    }
+}
+
+// The structured fields of a record, borrowed from it until they are changed
+#[cfg(feature = "kv")]
+#[derive(Clone)]
+struct Fields<'a>(Vec<(Cow<'a, str>, Field<'a>)>);
+
+#[cfg(feature = "kv")]
+#[derive(Clone)]
+enum Field<'a> {
+    Value(Value<'a>),
+    Owned(String),
+    #[cfg(feature = "redact")]
+    Error(RedactedError),
+}
+
+#[cfg(feature = "kv")]
+impl Field<'_> {
+    // The value as text. Values that aren't strings are rendered like the text based formatters do.
+    fn text(&self) -> Cow<'_, str> {
+        match self {
+            Field::Value(value) => value
+                .to_borrowed_str()
+                .map_or_else(|| Cow::Owned(kv_to_string(value)), Cow::Borrowed),
+            Field::Owned(text) => Cow::Borrowed(text),
+            #[cfg(feature = "redact")]
+            Field::Error(err) => Cow::Borrowed(&err.message),
+        }
+    }
+}
+
+// An error whose messages were redacted, keeping the shape of its source() chain
+#[cfg(all(feature = "kv", feature = "redact"))]
+#[derive(Debug, Clone)]
+struct RedactedError {
+    message: String,
+    source: Option<Box<RedactedError>>,
+}
+
+#[cfg(all(feature = "kv", feature = "redact"))]
+impl RedactedError {
+    // Returns None if nothing in the chain had to be redacted
+    fn new(redactor: &Redactor, err: &(dyn Error + 'static)) -> Option<Self> {
+        let mut changed = false;
+        let messages: Vec<_> = std::iter::successors(Some(err), |&err| err.source())
+            .map(|err| {
+                let message = err.to_string();
+                match redactor.redact_value(&message) {
+                    Cow::Borrowed(_) => message,
+                    Cow::Owned(redacted) => {
+                        changed = true;
+                        redacted
+                    },
+                }
+            })
+            .collect();
+
+        changed.then(|| {
+            let mut messages = messages.into_iter().rev();
+            let innermost = RedactedError {
+                message: messages.next().expect("The chain starts with the error itself"),
+                source: None,
+            };
+            messages.fold(innermost, |source, message| RedactedError {
+                message,
+                source: Some(Box::new(source)),
+            })
+        })
+    }
+}
+
+#[cfg(all(feature = "kv", feature = "redact"))]
+impl stdfmt::Display for RedactedError {
+    fn fmt(&self, f: &mut stdfmt::Formatter<'_>) -> stdfmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+#[cfg(all(feature = "kv", feature = "redact"))]
+impl Error for RedactedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_deref().map(|err| err as &(dyn Error + 'static))
+    }
+}
+
+#[cfg(feature = "kv")]
+impl<'a> VisitSource<'a> for Fields<'a> {
+    fn visit_pair(&mut self, key: Key<'a>, value: Value<'a>) -> Result<(), LogError> {
+        let key = key
+            .to_borrowed_str()
+            .map_or_else(|| Cow::Owned(key.to_string()), Cow::Borrowed);
+        self.0.push((key, Field::Value(value)));
+        Ok(())
+    }
+}
+
+#[cfg(feature = "kv")]
+impl Source for Fields<'_> {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn VisitSource<'kvs>) -> Result<(), LogError> {
+        for (key, field) in &self.0 {
+            let value = match field {
+                Field::Value(value) => value.clone(),
+                Field::Owned(text) => Value::from(text.as_str()),
+                #[cfg(feature = "redact")]
+                Field::Error(err) => Value::from_dyn_error(err),
+            };
+            visitor.visit_pair(Key::from_str(key), value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "kv", feature = "redact", feature = "logfmt"))]
+mod tests {
+    use super::*;
+    use crate::logfmt::decode;
+    use crate::{LogfmtFormatter, LokiFormatter, Mask};
+
+    #[test]
+    fn redact_before_formatting() {
+        let kvs: &[(&str, Value)] = &[
+            ("password", "my secret".into()),
+            ("note", "mail ann@example.com now".into()),
+            ("count", 3.into()),
+        ];
+        let record = log::Record::builder()
+            .args(format_args!("token for bob@example.com"))
+            .key_values(&kvs)
+            .build();
+        let mut prepared = Prepared::new(&record);
+        prepared.redact(&Redactor::with_defaults().mask(Mask::Partial(4)));
+
+        let formatter = LogfmtFormatter::default().render_line(true);
+        let line = formatter.log_line(&prepared).unwrap();
+        assert_eq!(decode(&line).unwrap()[1..], [
+            ("message".to_owned(), "token for ***********.com".to_owned()),
+            ("password".to_owned(), "*****cret".to_owned()),
+            ("note".to_owned(), "mail ***********.com now".to_owned()),
+            ("count".to_owned(), "3".to_owned()),
+        ]);
+    }
+
+    #[derive(Debug)]
+    struct Failed(&'static str, Option<Box<Failed>>);
+
+    impl stdfmt::Display for Failed {
+        fn fmt(&self, f: &mut stdfmt::Formatter<'_>) -> stdfmt::Result {
+            f.write_str(self.0)
+        }
+    }
+
+    impl Error for Failed {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            self.1.as_deref().map(|err| err as &(dyn Error + 'static))
+        }
+    }
+
+    #[test]
+    fn redact_error_sources() {
+        let err = Failed("login failed", Some(Box::new(Failed("no user ann@example.com", None))));
+        let kvs: &[(&str, Value)] = &[("err", Value::from_dyn_error(&err))];
+        let record = log::Record::builder().args(format_args!("m")).key_values(&kvs).build();
+        let mut prepared = Prepared::new(&record);
+        prepared.redact(&Redactor::with_defaults());
+
+        let formatter = LogfmtFormatter::default().render_line(true);
+        let line = formatter.log_line(&prepared).unwrap();
+        assert_eq!(decode(&line).unwrap()[2..], [
+            ("err".to_owned(), "login failed".to_owned()),
+            ("err_cause_0".to_owned(), "no user [REDACTED]".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn redact_formatter_attributes() {
+        let kvs: &[(&str, Value)] = &[("token", "*****".into())];
+        let record = log::Record::builder().args(format_args!("m")).key_values(&kvs).build();
+        let prepared = Prepared::new(&record);
+
+        let mut attributes: Attributes = [("token", "*****"), ("api_key", "abc"), ("host", "ann@example.com")]
+            .into_iter()
+            .collect();
+        prepared.redact_attributes(&Redactor::with_defaults(), &mut attributes);
+        // Attributes made from fields were redacted with the fields and are left alone
+        assert_eq!(attributes.iter().collect::<Vec<_>>(), [
+            ("token", "*****"),
+            ("api_key", "[REDACTED]"),
+            ("host", "[REDACTED]")
+        ]);
+    }
+}
@@ -0,0 +1,263 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use regex::{Captures, Regex};
+
+// Keys denied by `Redactor::with_defaults()`
+const DEFAULT_KEYS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "password",
+    "passwd",
+    "secret",
+    "token",
+    "access_token",
+    "refresh_token",
+    "api_key",
+    "apikey",
+];
+
+// Patterns used by `Redactor::with_defaults()`. Where a pattern has a capture group, only the
+// group is masked.
+const DEFAULT_PATTERNS: &[&str] = &[
+    // Credentials in authorization headers, e.g. `Authorization: Bearer <token>`
+    r"(?i)\b(?:bearer|basic)\s+([A-Za-z0-9._~+/-]+=*)",
+    // Email addresses
+    r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}",
+];
+
+/// `Mask` specifies what redacted values are replaced with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mask {
+    /// The value is replaced with the given text
+    Replace(String),
+    /// The value is removed. Fields with a denied key are dropped entirely.
+    Remove,
+    /// The value is replaced with a stable, non-cryptographic 64 bit hash, so that occurrences of
+    /// the same value can still be correlated. Don't rely on this for low entropy values.
+    Hash,
+    /// All but the last N characters of the value are replaced with `*`
+    Partial(usize),
+}
+
+impl Mask {
+    fn apply(&self, value: &str) -> String {
+        match self {
+            Mask::Replace(text) => text.clone(),
+            Mask::Remove => String::new(),
+            Mask::Hash => format!("[hash:{:016x}]", fnv1a(value.as_bytes())),
+            Mask::Partial(keep) => {
+                let len = value.chars().count();
+                let hidden = len.saturating_sub(*keep);
+                "*".repeat(hidden) + &value.chars().skip(hidden).collect::<String>()
+            },
+        }
+    }
+}
+
+impl Default for Mask {
+    fn default() -> Self {
+        Mask::Replace(String::from("[REDACTED]"))
+    }
+}
+
+/// `Redactor` strips secrets from logs before they leave the process. It runs on the message and
+/// the structured fields of each record before the `LokiFormatter` sees them, so masks apply to the
+/// raw values and the formatter quotes or encodes the result like any other value. For errors,
+/// every error in the `source()` chain is redacted. Attributes that the formatter adds on its own
+/// are redacted afterwards, as the formatter returns them.
+///
+/// Fields whose key is on the denylist are masked as a whole. Keys are compared case
+/// insensitively. Value patterns are applied to the message and to every field value; if a
+/// pattern has a capture group, only the first group is masked. Values that aren't strings are
+/// matched in their text form and become strings when masked.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    keys: Vec<String>,
+    patterns: Vec<Regex>,
+    mask: Mask,
+    counters: RedactionCounters,
+}
+
+impl Redactor {
+    /// Create a `Redactor` that doesn't redact anything.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Create a `Redactor` that denies common credential keys (`authorization`, `password`,
+    /// `token`, ...) and masks bearer and basic credentials as well as email addresses.
+    pub fn with_defaults() -> Self {
+        let mut redactor = DEFAULT_KEYS.iter().fold(Redactor::new(), |r, key| r.deny_key(key));
+        redactor.patterns = DEFAULT_PATTERNS
+            .iter()
+            .map(|pattern| Regex::new(pattern).expect("The default patterns are valid."))
+            .collect();
+        redactor
+    }
+
+    /// Mask the values of fields with the given key.
+    pub fn deny_key(mut self, key: &str) -> Self {
+        let key = key.to_lowercase();
+        if !self.keys.contains(&key) {
+            self.keys.push(key);
+        }
+        self
+    }
+
+    /// Mask everything matching `pattern` in the message and field values.
+    pub fn pattern(mut self, pattern: &str) -> Result<Self, regex::Error> {
+        self.patterns.push(Regex::new(pattern)?);
+        Ok(self)
+    }
+
+    /// Like `.pattern()`, but takes an already compiled regex.
+    pub fn regex(mut self, regex: Regex) -> Self {
+        self.patterns.push(regex);
+        self
+    }
+
+    /// Set what redacted values are replaced with. The default is `[REDACTED]`.
+    pub fn mask(mut self, mask: Mask) -> Self {
+        self.mask = mask;
+        self
+    }
+
+    /// Returns a handle to the counters of this redactor. Obtain one before passing the redactor
+    /// to `LokiBuilder::redactor()`.
+    pub fn counters(&self) -> RedactionCounters {
+        self.counters.clone()
+    }
+
+    // Returns true if the key is on the denylist
+    pub(crate) fn denies(&self, key: &str) -> bool {
+        self.keys.iter().any(|k| k.eq_ignore_ascii_case(key))
+    }
+
+    // Redacts the value of a structured field or attribute. Returns None if it should be dropped.
+    pub(crate) fn redact_field<'a>(&self, key: &str, value: &'a str) -> Option<Cow<'a, str>> {
+        if self.denies(key) {
+            self.counters.0.keys.fetch_add(1, Ordering::Relaxed);
+            return (self.mask != Mask::Remove).then(|| Cow::Owned(self.mask.apply(value)));
+        }
+
+        Some(self.redact_value(value))
+    }
+
+    // Masks everything matching a value pattern
+    pub(crate) fn redact_value<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        let mut value = Cow::Borrowed(value);
+
+        for pattern in &self.patterns {
+            let replaced = pattern.replace_all(&value, |caps: &Captures| {
+                self.counters.0.matches.fetch_add(1, Ordering::Relaxed);
+
+                let whole = caps.get(0).expect("Group 0 is always present.");
+                match caps.get(1) {
+                    Some(secret) => {
+                        let text = whole.as_str();
+                        let (start, end) = (secret.start() - whole.start(), secret.end() - whole.start());
+                        format!("{}{}{}", &text[..start], self.mask.apply(secret.as_str()), &text[end..])
+                    },
+                    None => self.mask.apply(whole.as_str()),
+                }
+            });
+
+            if let Cow::Owned(replaced) = replaced {
+                value = Cow::Owned(replaced);
+            }
+        }
+
+        value
+    }
+}
+
+/// `RedactionCounters` reports how much a `Redactor` has redacted. The counters are shared by all
+/// clones of the handle.
+#[derive(Debug, Clone, Default)]
+pub struct RedactionCounters(Arc<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    keys: AtomicU64,
+    matches: AtomicU64,
+}
+
+impl RedactionCounters {
+    /// The number of fields masked because their key was on the denylist.
+    pub fn keys(&self) -> u64 {
+        self.0.keys.load(Ordering::Relaxed)
+    }
+
+    /// The number of pattern matches masked in messages and field values.
+    pub fn matches(&self) -> u64 {
+        self.0.matches.load(Ordering::Relaxed)
+    }
+
+    /// The total number of redactions.
+    pub fn total(&self) -> u64 {
+        self.keys() + self.matches()
+    }
+}
+
+// 64 bit FNV-1a, which is stable across runs and platforms unlike std's DefaultHasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "kv")]
+    #[test]
+    fn redact_message_and_fields() {
+        let redactor = Redactor::with_defaults().pattern(r"sk_[a-z0-9]+").unwrap();
+        let counters = redactor.counters();
+
+        assert_eq!(
+            redactor.redact_value("GET / Authorization: Bearer abc.def key=sk_123"),
+            "GET / Authorization: Bearer [REDACTED] key=[REDACTED]"
+        );
+        assert_eq!(
+            redactor.redact_field("Authorization", "Basic dXNlcjpwYXNz").unwrap(),
+            "[REDACTED]"
+        );
+        assert_eq!(redactor.redact_field("user", "ann@example.com").unwrap(), "[REDACTED]");
+        assert!(matches!(
+            redactor.redact_field("path", "/home"),
+            Some(Cow::Borrowed("/home"))
+        ));
+        assert_eq!(counters.keys(), 1);
+        assert_eq!(counters.matches(), 3);
+        assert_eq!(counters.total(), 4);
+    }
+
+    #[test]
+    fn redact_masks() {
+        assert_eq!(Mask::Partial(4).apply("4111111111111111"), "************1111");
+        assert_eq!(Mask::Partial(4).apply("abc"), "abc");
+        assert_eq!(Mask::Hash.apply("a"), Mask::Hash.apply("a"));
+        assert_ne!(Mask::Hash.apply("a"), Mask::Hash.apply("b"));
+
+        #[cfg(feature = "kv")]
+        {
+            let redactor = Redactor::new().deny_key("password").mask(Mask::Remove);
+            assert_eq!(redactor.redact_field("Password", "hunter2"), None);
+            assert_eq!(redactor.redact_field("user", "ann").unwrap(), "ann");
+        }
+    }
+}
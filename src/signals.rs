@@ -0,0 +1,56 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use std::{io, thread};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use signal_hook::iterator::Signals;
+use signal_hook::low_level::emulate_default_handler;
+
+use crate::Loki;
+
+impl Loki {
+    /// Flushes the logs when the process receives SIGTERM or SIGINT, waiting up to `timeout` for
+    /// them to reach Loki. Afterwards, the signal is handled the way it would have been by
+    /// default, which usually terminates the process. A second signal received while the logs
+    /// are flushed is handled by default right away, so a second Ctrl-C still stops the process.
+    ///
+    /// This replaces the default handling of these signals, so it shouldn't be combined with other
+    /// handlers that terminate the process. The signals are watched on a background thread, which
+    /// doesn't keep the logger alive.
+    pub fn flush_on_termination(&self, timeout: Duration) -> io::Result<()> {
+        let received = Arc::new(AtomicBool::new(false));
+        for signal in [SIGTERM, SIGINT] {
+            // Actions run in the order they are registered, so only later signals see the flag set
+            flag::register_conditional_default(signal, Arc::clone(&received))?;
+            flag::register(signal, Arc::clone(&received))?;
+        }
+
+        let mut signals = Signals::new([SIGTERM, SIGINT])?;
+        let loki = self.downgrade();
+
+        thread::Builder::new()
+            .name(String::from("loki-signals"))
+            .spawn(move || {
+                if let Some(signal) = signals.forever().next() {
+                    // Nothing is left to flush once the logger and all its clones are dropped
+                    if loki.tx.get().is_some() && !loki.flush_timeout(timeout) {
+                        eprintln!("(Loki) Failed to flush logs within {timeout:?} before exiting");
+                    }
+                    // There is nothing left to do if the default action can't be emulated
+                    let _ = emulate_default_handler(signal);
+                }
+            })?;
+
+        Ok(())
+    }
+}
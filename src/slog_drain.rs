@@ -0,0 +1,231 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use std::borrow::Cow;
+use std::fmt;
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::sync::Arc;
+
+use log::kv::Source;
+use slog::{Drain, KV, Key, Level, Never, OwnedKVList, Serializer};
+
+use crate::fmt::{OwnedFields, OwnedValue};
+use crate::{FormatLog, Loki};
+
+/// `LokiDrain` is a slog `Drain` that sends records to Loki. Records go through the same
+/// filtering, formatting, batching and retry machinery as records from the log crate. The
+/// key-value pairs of the record and of the logger are passed to the formatter as structured
+/// fields, with the pairs of the record taking precedence.
+///
+/// slog records have no target, so the record's tag is used, or its module if it has no tag.
+///
+/// The drain shares the `Loki` logger, so keep a clone of the `Arc` around to flush it before the
+/// program exits.
+pub struct LokiDrain {
+    loki: Arc<Loki>,
+}
+
+impl LokiDrain {
+    /// Create a new drain that sends records to `loki`.
+    pub fn new(loki: impl Into<Arc<Loki>>) -> Self {
+        LokiDrain { loki: loki.into() }
+    }
+}
+
+// slog::Logger requires drains to be unwind safe. A panic can't leave the logger in a broken state,
+// since records are handed to the worker thread through a channel.
+impl UnwindSafe for LokiDrain {}
+impl RefUnwindSafe for LokiDrain {}
+
+impl Drain for LokiDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &slog::Record<'_>, values: &OwnedKVList) -> Result<(), Never> {
+        let log_metadata = log::Metadata::builder()
+            .level(log_level(record.level()))
+            .target(target(record))
+            .build();
+        if log::Log::enabled(&*self.loki, &log_metadata) {
+            self.loki.dispatch(&SlogRecord::new(record, values));
+        }
+        Ok(())
+    }
+}
+
+/// `SlogRecord` is a slog record captured for formatting. It implements `FormatLog`, so any
+/// `LokiFormatter` can format slog records.
+#[derive(Debug, Clone)]
+pub struct SlogRecord {
+    level: Level,
+    message: String,
+    target: String,
+    module: &'static str,
+    file: &'static str,
+    line: u32,
+    fields: OwnedFields,
+}
+
+impl SlogRecord {
+    /// Capture a record along with the key-value pairs of the logger it was logged with.
+    pub fn new(record: &slog::Record<'_>, values: &OwnedKVList) -> Self {
+        let mut fields = FieldSerializer(OwnedFields::default());
+        // Serializing only fails if the serializer does, which this one doesn't
+        let _ = values.serialize(record, &mut fields);
+        let _ = record.kv().serialize(record, &mut fields);
+
+        SlogRecord {
+            level: record.level(),
+            message: record.msg().to_string(),
+            target: String::from(target(record)),
+            module: record.module(),
+            file: record.file(),
+            line: record.line(),
+            fields: fields.0,
+        }
+    }
+}
+
+impl FormatLog for SlogRecord {
+    fn level(&self) -> Cow<'_, str> {
+        Cow::Owned(log_level(self.level).to_string().to_lowercase())
+    }
+
+    fn message(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.message)
+    }
+
+    fn target(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.target)
+    }
+
+    fn module(&self) -> Option<Cow<'_, str>> {
+        Some(Cow::Borrowed(self.module))
+    }
+
+    fn file(&self) -> Option<Cow<'_, str>> {
+        Some(Cow::Borrowed(self.file))
+    }
+
+    fn line(&self) -> Option<Cow<'_, str>> {
+        Some(Cow::Owned(self.line.to_string()))
+    }
+
+    fn key_values(&self) -> &dyn Source {
+        &self.fields
+    }
+}
+
+fn target<'a>(record: &'a slog::Record<'_>) -> &'a str {
+    match record.tag() {
+        "" => record.module(),
+        tag => tag,
+    }
+}
+
+// slog's Critical has no counterpart in the log crate and is treated as an error
+fn log_level(level: Level) -> log::Level {
+    match level {
+        Level::Critical | Level::Error => log::Level::Error,
+        Level::Warning => log::Level::Warn,
+        Level::Info => log::Level::Info,
+        Level::Debug => log::Level::Debug,
+        Level::Trace => log::Level::Trace,
+    }
+}
+
+// Collects key-value pairs, keeping the types slog distinguishes
+struct FieldSerializer(OwnedFields);
+
+impl FieldSerializer {
+    fn insert(&mut self, key: Key, value: OwnedValue) -> slog::Result {
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+impl Serializer for FieldSerializer {
+    fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments<'_>) -> slog::Result {
+        self.insert(key, OwnedValue::Str(val.to_string()))
+    }
+
+    fn emit_str(&mut self, key: Key, val: &str) -> slog::Result {
+        self.insert(key, OwnedValue::Str(String::from(val)))
+    }
+
+    fn emit_bool(&mut self, key: Key, val: bool) -> slog::Result {
+        self.insert(key, OwnedValue::Bool(val))
+    }
+
+    fn emit_i64(&mut self, key: Key, val: i64) -> slog::Result {
+        self.insert(key, OwnedValue::I64(val))
+    }
+
+    fn emit_i32(&mut self, key: Key, val: i32) -> slog::Result {
+        self.emit_i64(key, val.into())
+    }
+
+    fn emit_isize(&mut self, key: Key, val: isize) -> slog::Result {
+        self.emit_i64(key, val as i64)
+    }
+
+    fn emit_u64(&mut self, key: Key, val: u64) -> slog::Result {
+        self.insert(key, OwnedValue::U64(val))
+    }
+
+    fn emit_u32(&mut self, key: Key, val: u32) -> slog::Result {
+        self.emit_u64(key, val.into())
+    }
+
+    fn emit_usize(&mut self, key: Key, val: usize) -> slog::Result {
+        self.emit_u64(key, val as u64)
+    }
+
+    fn emit_f64(&mut self, key: Key, val: f64) -> slog::Result {
+        self.insert(key, OwnedValue::F64(val))
+    }
+
+    fn emit_f32(&mut self, key: Key, val: f32) -> slog::Result {
+        self.emit_f64(key, val.into())
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use serde_json::json;
+    use slog::o;
+
+    use super::*;
+    use crate::{AutoFields, JsonFormatter, test_builder};
+
+    #[test]
+    fn slog_record_fields() {
+        let builder = test_builder()
+            .formatter(Box::new(JsonFormatter::new(AutoFields::default() | AutoFields::TARGET)))
+            .directives("info,noisy=off".parse().unwrap());
+        let (loki, captured) = Loki::capture(builder);
+        let logger = slog::Logger::root(LokiDrain::new(loki), o!("service" => "api", "user" => "ann"));
+
+        slog::crit!(logger, "disk {} full", "/var"; "user" => "bob", "free" => 0u32, "ratio" => 0.5);
+        slog::debug!(logger, "filtered by level");
+        slog::info!(logger, # "noisy", "filtered by target");
+        slog::info!(logger, # "audit", "checked");
+
+        let logs = captured.json();
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0]["level"], "error");
+        assert_eq!(logs[0]["message"], "disk /var full");
+        assert_eq!(logs[0]["target"], module_path!());
+        assert_eq!(logs[0]["user"], "bob");
+        assert_eq!(logs[0]["service"], "api");
+        assert_eq!(logs[0]["ratio"], json!(0.5));
+        assert_eq!(logs[0]["free"], json!(0));
+
+        assert_eq!(logs[1]["target"], "audit");
+    }
+}
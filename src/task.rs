@@ -23,7 +23,7 @@ use serde::Serialize;
 use ureq::tls::TlsConfig;
 use ureq::{Agent, Error};
 
-use crate::FailurePolicy;
+use crate::{Attributes, FailurePolicy, FieldOrder};
 
 // LokiTask is a background thread that is used to send logs to Loki in the background
 pub struct LokiTask {
@@ -31,11 +31,13 @@ pub struct LokiTask {
     agent: Agent,
     endpoint: Uri,
     headers: HashMap<String, String>,
-    labels: HashMap<String, String>,
+    labels: Attributes,
     max_log_lines: usize,
     max_log_lifetime: Duration,
     failure_policy: FailurePolicy,
     flush_notif: Arc<(Mutex<bool>, Condvar)>,
+    #[cfg(feature = "compress")]
+    compress: bool,
 }
 
 impl LokiTask {
@@ -60,6 +62,10 @@ impl LokiTask {
 
         let agent = agent_builder.build().new_agent();
 
+        // Sort the labels so they are always sent in the same order
+        let mut labels: Attributes = labels.into_iter().collect();
+        labels.sort(&FieldOrder::Sorted);
+
         Self {
             rx,
             agent,
@@ -70,9 +76,18 @@ impl LokiTask {
             max_log_lifetime,
             failure_policy,
             flush_notif,
+            #[cfg(feature = "compress")]
+            compress: true,
         }
     }
 
+    // Enables or disables gzip compression of pushes
+    #[cfg(feature = "compress")]
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
     // Thread loop.
     // Tries to receive messages from the channel, flushing before any limits are violated.
     // When not processing items from the channel, we'll retry failed items if there are any and check the age
@@ -154,7 +169,7 @@ impl LokiTask {
 
         // perform gzip compression
         #[cfg(feature = "compress")]
-        {
+        if self.compress {
             let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
             match encoder.write_all(&serialized) {
                 Ok(()) => match encoder.finish() {
@@ -180,7 +195,7 @@ impl LokiTask {
         }
         request = request.content_type("application/json; charset=utf-8");
         #[cfg(feature = "compress")]
-        {
+        if self.compress {
             request = request.header("Content-Encoding", "gzip");
         }
 
@@ -287,7 +302,7 @@ impl LokiTask {
 // LokiTaskMsg is used by the main thread to send messages to the LokiTask
 #[derive(Clone, Debug)]
 pub enum LokiTaskMsg {
-    Log(u128, String, HashMap<String, String>),
+    Log(u128, String, Attributes),
     Flush,
 }
 
@@ -308,7 +323,7 @@ struct LokiPush {
 
 impl LokiPush {
     #[allow(unused_variables)]
-    pub fn new(labels: &HashMap<String, String>, max_log_lines: usize) -> Self {
+    pub fn new(labels: &Attributes, max_log_lines: usize) -> Self {
         Self {
             #[cfg(feature = "multistream")]
             streams: Vec::with_capacity(max_log_lines),
@@ -322,13 +337,7 @@ impl LokiPush {
         }
     }
 
-    pub fn add_log(
-        &mut self,
-        time: u128,
-        log_line: String,
-        labels: &HashMap<String, String>,
-        mut attributes: HashMap<String, String>,
-    ) {
+    pub fn add_log(&mut self, time: u128, log_line: String, labels: &Attributes, mut attributes: Attributes) {
         #[cfg(feature = "multistream")]
         {
             attributes.extend(labels.clone());
@@ -340,14 +349,8 @@ impl LokiPush {
 
         #[cfg(not(feature = "multistream"))]
         {
-            let message = format!(
-                "{log_line} {}",
-                attributes
-                    .iter()
-                    .map(|(k, v)| format!("{k}={v}"))
-                    .collect::<Vec<String>>()
-                    .join(" ")
-            );
+            let mut message = log_line;
+            append_attributes(&mut message, &attributes);
             self.streams[0].values.push([time.to_string(), message]);
         }
     }
@@ -369,9 +372,26 @@ impl LokiPush {
     }
 }
 
+// Appends attributes to a log line, which is how they are sent without the multistream feature
+#[cfg(not(feature = "multistream"))]
+pub fn append_attributes(log_line: &mut String, attributes: &Attributes) {
+    for (k, v) in attributes.iter() {
+        log_line.push_str(&format!(" {k}={v}"));
+    }
+}
+
+// Returns the length of a log line as it is sent to Loki
+pub fn entry_len(log_line: &str, attributes: &Attributes) -> usize {
+    if cfg!(feature = "multistream") {
+        log_line.len()
+    } else {
+        log_line.len() + attributes.iter().map(|(k, v)| k.len() + v.len() + 2).sum::<usize>()
+    }
+}
+
 #[derive(Serialize, Clone)]
 struct LokiStream {
-    stream: HashMap<String, String>,
+    stream: Attributes,
 
     #[cfg(feature = "multistream")]
     values: [[String; 2]; 1],
@@ -388,3 +408,25 @@ struct FailedPush {
     #[derivative(PartialEq = "ignore", PartialOrd = "ignore", Ord = "ignore")]
     push: Box<LokiPush>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_serialization_order() {
+        let labels: Attributes = [("b", "2"), ("a", "1")].into_iter().collect();
+        let attributes: Attributes = [("z", "26"), ("level", "info")].into_iter().collect();
+
+        let mut lp = LokiPush::new(&labels, 1);
+        lp.add_log(5, "message".to_owned(), &labels, attributes);
+
+        #[cfg(feature = "multistream")]
+        let expected =
+            r#"{"streams":[{"stream":{"z":"26","level":"info","b":"2","a":"1"},"values":[["5","message"]]}]}"#;
+        #[cfg(not(feature = "multistream"))]
+        let expected = r#"{"streams":[{"stream":{"b":"2","a":"1"},"values":[["5","message z=26 level=info"]]}]}"#;
+
+        assert_eq!(serde_json::to_string(&lp).unwrap(), expected);
+    }
+}
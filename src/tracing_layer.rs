@@ -0,0 +1,309 @@
+/*
+Copyright (C) 2022 Aurora McGinnis
+
+This Source Code Form is subject to the terms of the Mozilla Public
+License, v. 2.0. If a copy of the MPL was not distributed with this
+file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+
+use std::borrow::Cow;
+use std::fmt::{self, Write};
+use std::sync::Arc;
+
+use log::kv::Source;
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::fmt::{OwnedFields as Fields, OwnedValue as FieldValue};
+use crate::{FormatLog, Loki};
+
+/// `LokiLayer` is a `tracing_subscriber` `Layer` that sends tracing events to Loki. Events go
+/// through the same filtering, formatting, batching and retry machinery as records from the log
+/// crate. The fields of the spans an event is in are flattened into its structured fields, with
+/// fields of inner spans and of the event itself taking precedence.
+///
+/// The layer shares the `Loki` logger, so keep a clone of the `Arc` around to flush it before the
+/// program exits:
+///
+/// ```ignore
+/// let loki = Arc::new(LokiBuilder::new(endpoint, labels).build());
+/// tracing_subscriber::registry().with(LokiLayer::new(loki.clone())).init();
+/// // ...
+/// loki.send_and_white_flush();
+/// ```
+pub struct LokiLayer {
+    loki: Arc<Loki>,
+}
+
+impl LokiLayer {
+    /// Create a new layer that sends events to `loki`.
+    pub fn new(loki: impl Into<Arc<Loki>>) -> Self {
+        LokiLayer { loki: loki.into() }
+    }
+}
+
+impl<S> Layer<S> for LokiLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        new_span(attrs, id, &ctx);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        record_span(id, values, &ctx);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        // Filtering happens here rather than in Layer::enabled, which would also disable the
+        // event for every other layer of the subscriber
+        let metadata = event.metadata();
+        let log_metadata = log::Metadata::builder()
+            .level(log_level(metadata.level()))
+            .target(metadata.target())
+            .build();
+        if !log::Log::enabled(&*self.loki, &log_metadata) {
+            return;
+        }
+
+        self.loki.dispatch(&TracingRecord::with_spans(event, &ctx));
+    }
+}
+
+/// `TracingRecord` is a tracing event captured for formatting. It implements `FormatLog`, so any
+/// `LokiFormatter` can format tracing events. The `message` field of the event becomes the message,
+/// all other fields become structured fields.
+#[derive(Debug, Clone)]
+pub struct TracingRecord {
+    metadata: &'static Metadata<'static>,
+    message: String,
+    fields: Fields,
+}
+
+impl TracingRecord {
+    /// Capture the fields of an event, ignoring the spans it is in.
+    pub fn from_event(event: &Event<'_>) -> Self {
+        TracingRecord::capture(event, Fields::default())
+    }
+
+    /// Capture the fields of an event and of the spans it is in.
+    pub fn with_spans<S>(event: &Event<'_>, ctx: &Context<'_, S>) -> Self
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let mut fields = Fields::default();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(span_fields) = span.extensions().get::<SpanFields>() {
+                    fields.extend(&span_fields.0);
+                }
+            }
+        }
+
+        TracingRecord::capture(event, fields)
+    }
+
+    fn capture(event: &Event<'_>, mut fields: Fields) -> Self {
+        let mut message = String::new();
+        event.record(&mut FieldVisitor {
+            fields: &mut fields,
+            message: Some(&mut message),
+        });
+
+        TracingRecord {
+            metadata: event.metadata(),
+            message,
+            fields,
+        }
+    }
+}
+
+impl FormatLog for TracingRecord {
+    fn level(&self) -> Cow<'_, str> {
+        Cow::Owned(self.metadata.level().as_str().to_lowercase())
+    }
+
+    fn message(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.message)
+    }
+
+    fn target(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self.metadata.target())
+    }
+
+    fn module(&self) -> Option<Cow<'_, str>> {
+        self.metadata.module_path().map(Cow::Borrowed)
+    }
+
+    fn file(&self) -> Option<Cow<'_, str>> {
+        self.metadata.file().map(Cow::Borrowed)
+    }
+
+    fn line(&self) -> Option<Cow<'_, str>> {
+        self.metadata.line().map(|line| Cow::Owned(line.to_string()))
+    }
+
+    fn key_values(&self) -> &dyn Source {
+        &self.fields
+    }
+}
+
+// Stores the fields of a new span in its extensions
+fn new_span<S>(attrs: &Attributes<'_>, id: &Id, ctx: &Context<'_, S>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let Some(span) = ctx.span(id) else {
+        return;
+    };
+
+    // Another LokiLayer on the same subscriber may have stored them already
+    let mut extensions = span.extensions_mut();
+    if extensions.get_mut::<SpanFields>().is_some() {
+        return;
+    }
+
+    let mut fields = SpanFields::default();
+    attrs.record(&mut FieldVisitor {
+        fields: &mut fields.0,
+        message: None,
+    });
+    extensions.insert(fields);
+}
+
+// Updates the stored fields of a span when values are recorded later on
+fn record_span<S>(id: &Id, values: &Record<'_>, ctx: &Context<'_, S>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let Some(span) = ctx.span(id) else {
+        return;
+    };
+
+    if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+        values.record(&mut FieldVisitor {
+            fields: &mut fields.0,
+            message: None,
+        });
+    }
+}
+
+fn log_level(level: &Level) -> log::Level {
+    match *level {
+        Level::ERROR => log::Level::Error,
+        Level::WARN => log::Level::Warn,
+        Level::INFO => log::Level::Info,
+        Level::DEBUG => log::Level::Debug,
+        Level::TRACE => log::Level::Trace,
+    }
+}
+
+// Fields recorded on a span, stored in the span's extensions
+#[derive(Default)]
+struct SpanFields(Fields);
+
+// Records fields. If message is set, the `message` field is written there instead.
+struct FieldVisitor<'a> {
+    fields: &'a mut Fields,
+    message: Option<&'a mut String>,
+}
+
+impl FieldVisitor<'_> {
+    fn record(&mut self, field: &Field, value: FieldValue) {
+        self.fields.insert(field.name(), value);
+    }
+}
+
+impl Visit for FieldVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, FieldValue::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, FieldValue::U64(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record(field, FieldValue::F64(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, FieldValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match &mut self.message {
+            Some(message) if field.name() == "message" => message.push_str(value),
+            _ => self.record(field, FieldValue::Str(value.to_owned())),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match &mut self.message {
+            Some(message) if field.name() == "message" => {
+                write!(message, "{value:?}").expect("Writing to a String can't fail.")
+            },
+            _ => self.record(field, FieldValue::Str(format!("{value:?}"))),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use log::LevelFilter;
+    use serde_json::json;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::{JsonFormatter, test_builder};
+
+    #[test]
+    fn tracing_span_fields() {
+        let builder = test_builder()
+            .formatter(Box::new(JsonFormatter::default()))
+            .level(LevelFilter::Info);
+        let (loki, captured) = Loki::capture(builder);
+        let subscriber = tracing_subscriber::registry().with(LokiLayer::new(loki));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let outer = tracing::info_span!("request", id = 7u64, user = "ann");
+            let _outer = outer.enter();
+            let inner = tracing::debug_span!("query", user = "bob", ok = tracing::field::Empty);
+            inner.record("ok", true);
+            let _inner = inner.enter();
+            tracing::debug!("filtered out");
+            tracing::warn!(rows = -1i64, "query {} failed", "q1");
+        });
+
+        let logs = captured.json();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0]["level"], "warn");
+        assert_eq!(logs[0]["message"], "query q1 failed");
+        assert_eq!(logs[0]["id"], json!(7));
+        assert_eq!(logs[0]["user"], "bob");
+        assert_eq!(logs[0]["ok"], true);
+        assert_eq!(logs[0]["rows"], json!(-1));
+    }
+
+    #[test]
+    fn tracing_two_layers() {
+        let (first, first_logs) = Loki::capture(test_builder().formatter(Box::new(JsonFormatter::default())));
+        let (second, second_logs) = Loki::capture(test_builder().formatter(Box::new(JsonFormatter::default())));
+        let subscriber = tracing_subscriber::registry()
+            .with(LokiLayer::new(first))
+            .with(LokiLayer::new(second));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("request", id = 7u64).entered();
+            tracing::info!("handled");
+        });
+
+        for logs in [first_logs.json(), second_logs.json()] {
+            assert_eq!(logs.len(), 1);
+            assert_eq!(logs[0]["id"], json!(7));
+        }
+    }
+}